@@ -46,12 +46,16 @@ impl Block {
 
     pub fn hash_transactions(&self) -> Vec<u8> {
         let mut tx_hashes = Vec::new();
+        let mut auth_hashes = Vec::new();
 
         for tx in &self.transactions {
             tx_hashes.push(tx.id.clone());
+            auth_hashes.push(tx.auth_digest().to_vec());
         }
 
-        let concatenated_hashes = tx_hashes.concat();
+        // Transaction ids no longer cover signatures and proofs, so the block commits to
+        // the authorizing data separately.
+        let concatenated_hashes = [tx_hashes.concat(), auth_hashes.concat()].concat();
         Sha256::digest(concatenated_hashes).to_vec()
     }
 }
@@ -1,12 +1,26 @@
 use crate::{
     block::Block,
+    coinselect::{Outpoint, Utxo},
+    error::{Error, Result},
     iterator::BlockchainIterator,
+    merkle::Frontier,
     transaction::{new_coinbase_tx, Transaction},
-    transaction_output::TXOutput,
+    verify, wallets,
 };
-use std::{collections::HashMap, error::Error};
+use orchard::tree::Anchor;
+use sled::Transactional;
+use std::collections::{HashMap, HashSet};
 
 const COINBASEDATA: &str = "coinbase";
+const DB_FILE: &str = "db.file";
+const BLOCKS_BUCKET: &str = "blocksBucket";
+/// Chain state derived from the blocks, so validation need not replay the chain. Keys are
+/// prefixed by kind: `s` for spent outpoints, `n` for revealed nullifiers and `a` for the
+/// note commitment tree's root as of each block. `f` holds the tree's frontier at the tip,
+/// `l` the tip the state is up to date with and `v` the layout version, and the state is
+/// rebuilt when either of the last two differs.
+const STATE_BUCKET: &str = "stateBucket";
+const STATE_VERSION: &[u8] = &[3];
 
 pub struct Blockchain {
     pub tip: Vec<u8>,
@@ -14,186 +28,464 @@ pub struct Blockchain {
 }
 
 impl Blockchain {
-    pub fn new(address: &str) -> Self {
-        let db = match sled::open("db.file") {
-            Ok(db) => db,
-            Err(e) => panic!("Failed to open database: {}", e),
-        };
-        let tip: Vec<u8>;
-        let b = db.open_tree("blocksBucket").unwrap();
+    /// Opens the chain, creating it with a genesis block paying `address` if there is none.
+    pub fn new(address: &str) -> Result<Self> {
+        Self::create(sled::open(DB_FILE)?, address)
+    }
 
-        if b.is_empty() {
-            let genesis = Block::genesis(new_coinbase_tx(address, COINBASEDATA, 10));
-            b.insert(&genesis.hash, genesis.serialize()).unwrap();
-            b.insert(b"l", genesis.hash.as_slice()).unwrap();
-            tip = genesis.hash.to_vec();
-            b.flush().unwrap();
-        } else {
-            tip = b.get(b"l").unwrap().unwrap().to_vec();
-        }
+    /// Like [`Blockchain::new`], for the chain kept in `db`.
+    fn create(db: sled::Db, address: &str) -> Result<Self> {
+        let b = db.open_tree(BLOCKS_BUCKET)?;
+
+        let tip = match b.get(b"l")? {
+            Some(tip) => tip.to_vec(),
+            None => {
+                let genesis = Block::genesis(new_coinbase_tx(address, COINBASEDATA, 10)?);
+                b.insert(&genesis.hash, genesis.serialize())?;
+                b.insert(b"l", genesis.hash.as_slice())?;
+                b.flush()?;
+                genesis.hash.to_vec()
+            }
+        };
 
-        Blockchain { tip, db }
+        let bc = Blockchain { tip, db };
+        bc.reindex()?;
+        Ok(bc)
     }
 
-    pub fn find_utxo(&self, pub_key_hash: &Vec<u8>) -> Vec<TXOutput> {
-        let mut utxos = Vec::new();
-        let unspent_transactions = self.find_unspent_transactions(pub_key_hash);
+    /// Opens an existing chain, failing if none has been created.
+    pub fn open() -> Result<Self> {
+        let db = sled::open(DB_FILE)?;
+        let tip = db
+            .open_tree(BLOCKS_BUCKET)?
+            .get(b"l")?
+            .ok_or(Error::NoBlockchain)?
+            .to_vec();
+
+        let bc = Blockchain { tip, db };
+        bc.reindex()?;
+        Ok(bc)
+    }
 
-        for tx in unspent_transactions {
-            for out in tx.vout {
-                if out.is_locked_with_key(pub_key_hash) {
-                    utxos.push(out.clone()); // Assuming TXOutput implements Clone trait
-                }
-            }
+    /// Number of blocks on the chain, without creating a genesis block if there is none.
+    pub fn height() -> Result<u64> {
+        match Blockchain::open() {
+            Ok(bc) => Ok(bc.blocks()?.len() as u64),
+            Err(Error::NoBlockchain) => Ok(0),
+            Err(e) => Err(e),
         }
+    }
 
-        utxos
+    /// The unspent outputs locked to `pub_key_hash`, oldest first.
+    pub fn find_utxos(&self, pub_key_hash: &Vec<u8>) -> Result<Vec<Utxo>> {
+        Ok(find_utxos_in(&self.blocks()?, pub_key_hash))
     }
 
-    pub fn mine_block(&mut self, transactions: Vec<Transaction>) {
-        let b = self.db.open_tree("blocksBucket").unwrap();
-        let prev_block_hash = b.get(b"l").unwrap().unwrap().to_vec();
+    /// Appends a block of `transactions`, refusing it if any of them fails validation,
+    /// including conflicts with the block's earlier transactions.
+    pub fn mine_block(&mut self, transactions: Vec<Transaction>) -> Result<()> {
+        let blocks = self.db.open_tree(BLOCKS_BUCKET)?;
+        let state = self.db.open_tree(STATE_BUCKET)?;
+        let prev_block_hash = self.tip.clone();
 
+        let mut spent = HashSet::new();
         for tx in &transactions {
-            if !self.verify_transaction(tx) {
-                panic!("Invalid transaction");
-            }
+            self.check_transaction(tx, &spent)?;
+            spent.extend(spent_keys(tx));
         }
 
         let new_block = Block::new(transactions, prev_block_hash);
+
+        // The block, the new tip and the chain state are written in one transaction, so a
+        // crash cannot leave the tip pointing at a missing block or the state behind it.
+        let mut block_batch = sled::Batch::default();
+        block_batch.insert(new_block.hash.as_slice(), new_block.serialize());
+        block_batch.insert(b"l", new_block.hash.as_slice());
+        let mut frontier = self.frontier()?;
+        let mut state_batch = sled::Batch::default();
+        index_block(&new_block, &mut frontier, &mut state_batch);
+        state_batch.insert(b"f", serde_json::to_vec(&frontier)?);
+        state_batch.insert(b"l", new_block.hash.as_slice());
+        (&blocks, &state).transaction(|(blocks, state)| {
+            blocks.apply_batch(&block_batch)?;
+            state.apply_batch(&state_batch)?;
+            Ok(())
+        })?;
+        self.db.flush()?;
+
         self.tip = new_block.hash.to_vec();
+        Ok(())
+    }
+
+    /// Rebuilds the chain state from the blocks unless it is up to date with the tip.
+    fn reindex(&self) -> Result<()> {
+        let state = self.db.open_tree(STATE_BUCKET)?;
+        let current = state.get(b"v")?.is_some_and(|v| v == STATE_VERSION)
+            && state.get(b"l")?.is_some_and(|tip| tip == self.tip);
+        if current {
+            return Ok(());
+        }
+
+        state.clear()?;
+        let mut frontier = Frontier::default();
+        let mut batch = sled::Batch::default();
+        for block in self.blocks()? {
+            index_block(&block, &mut frontier, &mut batch);
+        }
+        batch.insert(b"f", serde_json::to_vec(&frontier)?);
+        batch.insert(b"l", self.tip.as_slice());
+        batch.insert(b"v", STATE_VERSION);
+        state.apply_batch(batch)?;
+        state.flush()?;
+        Ok(())
+    }
+
+    /// Whether an output or a note was spent by a transaction on chain.
+    fn is_spent(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.db.open_tree(STATE_BUCKET)?.contains_key(key)?)
+    }
 
-        b.insert(&new_block.hash, new_block.serialize()).unwrap();
-        b.insert(b"l", new_block.hash.as_slice()).unwrap();
-        b.flush().unwrap();
+    /// Whether `anchor` is the root of the note commitment tree as of some block, so
+    /// spends can only be witnessed against commitments that are really on chain.
+    fn is_known_anchor(&self, anchor: &Anchor) -> Result<bool> {
+        Ok(self
+            .db
+            .open_tree(STATE_BUCKET)?
+            .contains_key(anchor_key(anchor))?)
+    }
+
+    /// The note commitment tree's frontier at the tip.
+    fn frontier(&self) -> Result<Frontier> {
+        match self.db.open_tree(STATE_BUCKET)?.get(b"f")? {
+            Some(frontier) => Ok(serde_json::from_slice(&frontier)?),
+            None => Ok(Frontier::default()),
+        }
     }
 
-    pub fn iterator(&self) -> BlockchainIterator {
+    pub fn iterator(&self) -> BlockchainIterator<'_> {
         BlockchainIterator {
             current_hash: self.tip.clone(),
             db: &self.db,
         }
     }
 
-    pub fn find_spendable_outputs(
-        &self,
-        address: &Vec<u8>,
-        amount: i64,
-    ) -> (i64, HashMap<String, Vec<i32>>) {
-        let mut unspent_outputs = HashMap::new();
-        let unspent_txs = self.find_unspent_transactions(address);
-        let mut accumulated = 0;
-
-        'Work: for tx in &unspent_txs {
-            let tx_id = hex::encode(tx.id.clone());
+    /// Returns the chain's blocks from genesis to tip, so a block's index is its height.
+    pub fn blocks(&self) -> Result<Vec<Block>> {
+        let mut blocks = self.iterator().collect::<Result<Vec<_>>>()?;
+        blocks.reverse();
+        Ok(blocks)
+    }
 
-            for (out_idx, out) in tx.vout.iter().enumerate() {
-                if out.is_locked_with_key(address) && accumulated < amount {
-                    accumulated += out.value;
-                    let outputs = unspent_outputs.entry(tx_id.clone()).or_insert(Vec::new());
-                    outputs.push(out_idx as i32);
-
-                    if accumulated >= amount {
-                        break 'Work;
-                    }
+    pub(crate) fn find_transaction(&self, id: &Vec<u8>) -> Result<Option<Transaction>> {
+        for block in self.iterator() {
+            for tx in block?.transactions {
+                if tx.id == *id {
+                    return Ok(Some(tx));
                 }
             }
         }
 
-        (accumulated, unspent_outputs)
+        Ok(None)
     }
 
-    fn find_unspent_transactions(&self, address: &Vec<u8>) -> Vec<Transaction> {
-        let mut unspent_txs: Vec<Transaction> = Vec::new();
-        let mut spent_txos = HashMap::new();
-        let mut bci = self.iterator();
+    pub fn sign_transaction(&self, tx: &mut Transaction, priv_key: String) -> Result<()> {
+        let priv_key = wallets::parse_private_key(&priv_key)?;
+        tx.sign(priv_key, &self.prev_txs(tx)?)
+    }
 
-        while let Some(block) = bci.next() {
-            for tx in &block.transactions {
-                let tx_id = hex::encode(tx.id.clone());
+    /// The transactions whose outputs `tx` spends, by hex id, for signing its inputs.
+    pub(crate) fn prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
+        let mut prev_txs = HashMap::new();
+        for vin in tx.vin.iter() {
+            if let Some(prev_tx) = self.find_transaction(&vin.txid)? {
+                prev_txs.insert(hex::encode(&prev_tx.id), prev_tx);
+            }
+        }
+        Ok(prev_txs)
+    }
 
-                // 检查所有输出是否被花费
-                for (out_idx, out) in tx.vout.iter().enumerate() {
-                    if spent_txos
-                        .get(&tx_id)
-                        .map_or(false, |v: &Vec<i32>| v.contains(&(out_idx as i32)))
-                    {
-                        continue;
-                    }
-                    if out.is_locked_with_key(address) {
-                        unspent_txs.push(tx.clone());
-                    }
-                }
+    /// Whether `tx` could be mined on top of the current tip.
+    pub fn verify_transaction(&self, tx: &Transaction) -> bool {
+        self.check_transaction(tx, &HashSet::new()).is_ok()
+    }
 
-                // 更新已花费的输出
-                if !tx.is_coinbase() {
-                    for input in &tx.vin {
-                        if input.use_key(address) {
-                            let in_tx_id = hex::encode(input.txid.clone());
-                            spent_txos
-                                .entry(in_tx_id)
-                                .or_insert_with(Vec::new)
-                                .push(input.vout);
-                        }
-                    }
+    /// Validates `tx` against the chain and the outputs `spent` by transactions mined
+    /// before it in the same block, saying why it is invalid.
+    fn check_transaction(&self, tx: &Transaction, spent: &HashSet<Vec<u8>>) -> Result<()> {
+        let invalid = |reason: &str| {
+            Err(Error::InvalidTransaction(format!(
+                "{}: {}",
+                hex::encode(&tx.id),
+                reason
+            )))
+        };
+        if tx.id != tx.txid_digest() {
+            return invalid("id does not match its contents");
+        }
+        // Only the genesis block mints coins.
+        if tx.is_coinbase() {
+            return invalid("coinbase outside the genesis block");
+        }
+        // The bundle is signed with the same sighash as the transparent inputs, so it
+        // cannot be detached from them and attached to another transaction.
+        if !tx.bundle.is_empty() {
+            let bundle = match tx.bundle.to_orchard() {
+                Some(bundle) if verify::verify_bundle(&bundle, &tx.sighash()) => bundle,
+                _ => return invalid("invalid shielded bundle"),
+            };
+            if bundle.flags().spends_enabled() && !self.is_known_anchor(bundle.anchor())? {
+                return invalid("unknown anchor");
+            }
+            // Each note has one nullifier, so revealing one twice is a double spend.
+            let mut seen = HashSet::new();
+            for action in bundle.actions() {
+                let key = nullifier_key(&action.nullifier().to_bytes());
+                if !seen.insert(key.clone()) {
+                    return invalid("reveals a nullifier twice");
+                }
+                if spent.contains(&key) || self.is_spent(&key)? {
+                    return invalid("spends a note that is already spent");
                 }
             }
-            if block.prev_block_hash.is_empty() {
-                break;
+        }
+
+        let mut prev_txs = HashMap::new();
+        let mut inputs = 0i64;
+        let mut seen = HashSet::new();
+        for vin in tx.vin.iter() {
+            let key = spent_key(&vin.txid, vin.vout);
+            if !seen.insert(key.clone()) {
+                return invalid("spends an output twice");
             }
+            if spent.contains(&key) || self.is_spent(&key)? {
+                return invalid("spends an output that is already spent");
+            }
+            let prev_tx = match self.find_transaction(&vin.txid)? {
+                Some(prev_tx) => prev_tx,
+                None => return invalid("spends an unknown transaction"),
+            };
+            let value = match usize::try_from(vin.vout)
+                .ok()
+                .and_then(|vout| prev_tx.vout.get(vout))
+            {
+                Some(out) => out.value,
+                None => return invalid("spends a missing output"),
+            };
+            inputs = match inputs.checked_add(value) {
+                Some(inputs) if value >= 0 => inputs,
+                _ => return invalid("input value out of range"),
+            };
+            prev_txs.insert(hex::encode(&prev_tx.id), prev_tx);
         }
 
-        unspent_txs
-    }
+        let mut outputs = 0i64;
+        for out in &tx.vout {
+            outputs = match outputs.checked_add(out.value) {
+                Some(outputs) if out.value >= 0 => outputs,
+                _ => return invalid("output value out of range"),
+            };
+        }
 
-    fn find_transaction(&self, id: &Vec<u8>) -> Result<Transaction, Box<dyn Error>> {
-        let mut bci = self.iterator();
+        // Value may only leave the pool as far as the bundle releases it: the inputs plus
+        // the bundle's value balance must cover the outputs.
+        match inputs.checked_add(tx.bundle.value_balance()) {
+            Some(available) if available >= outputs => {}
+            _ => return invalid("outputs exceed inputs"),
+        }
 
-        loop {
-            if let Some(block) = bci.next() {
-                for tx in &block.transactions {
-                    if tx.id == *id {
-                        return Ok(tx.clone());
-                    }
-                }
+        if !tx.verify(&prev_txs) {
+            return invalid("invalid input signature");
+        }
+        Ok(())
+    }
+}
 
-                if block.prev_block_hash.is_empty() {
-                    break;
+/// The outputs locked to `pub_key_hash` that no transaction in `blocks` spends, in chain
+/// order.
+pub fn find_utxos_in(blocks: &[Block], pub_key_hash: &Vec<u8>) -> Vec<Utxo> {
+    let mut utxos: Vec<Utxo> = Vec::new();
+    for (height, block) in blocks.iter().enumerate() {
+        for tx in &block.transactions {
+            if !tx.is_coinbase() {
+                for input in &tx.vin {
+                    let spent = Outpoint {
+                        txid: hex::encode(&input.txid),
+                        vout: input.vout,
+                    };
+                    utxos.retain(|u| u.outpoint != spent);
+                }
+            }
+            for (out_idx, out) in tx.vout.iter().enumerate() {
+                if out.is_locked_with_key(pub_key_hash) {
+                    utxos.push(Utxo {
+                        outpoint: Outpoint {
+                            txid: hex::encode(&tx.id),
+                            vout: out_idx as i32,
+                        },
+                        value: out.value,
+                        height: height as u64,
+                    });
                 }
             }
         }
-
-        Err("TransactionNotFound".into())
     }
+    utxos
+}
 
-    pub fn sign_transaction(&self, tx: &mut Transaction, priv_key: String) {
-        let priv_key =
-            secp256k1::SecretKey::from_slice(hex::decode(priv_key).unwrap().as_slice()).unwrap();
-        let mut prev_txs = HashMap::new();
-
-        for vin in tx.vin.iter() {
-            let prev_tx_result = self.find_transaction(&vin.txid);
-            if let Ok(prev_tx) = prev_tx_result {
-                prev_txs.insert(hex::encode(&prev_tx.id), prev_tx);
+/// Records the effects of `block` on the chain state, appending its note commitments to
+/// `frontier`.
+fn index_block(block: &Block, frontier: &mut Frontier, batch: &mut sled::Batch) {
+    for tx in &block.transactions {
+        for key in spent_keys(tx) {
+            batch.insert(key, &[]);
+        }
+        if let Some(bundle) = tx.bundle.to_orchard() {
+            for action in bundle.actions() {
+                frontier.append(action.cmx());
             }
         }
+    }
+    batch.insert(anchor_key(&frontier.root()), &[]);
+}
+
+/// The state keys of the outputs and the notes `tx` spends.
+fn spent_keys(tx: &Transaction) -> Vec<Vec<u8>> {
+    let mut keys = vec![];
+    if !tx.is_coinbase() {
+        keys.extend(tx.vin.iter().map(|vin| spent_key(&vin.txid, vin.vout)));
+    }
+    if let Some(bundle) = tx.bundle.to_orchard() {
+        keys.extend(
+            bundle
+                .actions()
+                .iter()
+                .map(|action| nullifier_key(&action.nullifier().to_bytes())),
+        );
+    }
+    keys
+}
+
+fn spent_key(txid: &[u8], vout: i32) -> Vec<u8> {
+    [b"s".as_slice(), txid, &vout.to_be_bytes()].concat()
+}
+
+fn nullifier_key(nullifier: &[u8; 32]) -> Vec<u8> {
+    [b"n".as_slice(), nullifier].concat()
+}
 
-        tx.sign(priv_key, &prev_txs);
+fn anchor_key(anchor: &Anchor) -> Vec<u8> {
+    [b"a".as_slice(), &anchor.to_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction_input::TXInput;
+    use crate::transaction_output::TXOutput;
+    use crate::wallet::{tests::wallet, Wallet};
+    use orchard::{builder::Builder, bundle::Flags, circuit::ProvingKey, value::NoteValue};
+    use rand::rngs::OsRng;
+
+    /// A chain kept in memory, whose genesis block pays 10 to `to`.
+    fn chain(to: &Wallet) -> Blockchain {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Blockchain::create(db, &to.get_address()).unwrap()
     }
 
-    pub fn verify_transaction(&self, tx: &Transaction) -> bool {
-        if tx.is_coinbase() {
-            return true;
-        }
-        let mut prev_txs = HashMap::new();
+    fn genesis_output(bc: &Blockchain) -> Vec<u8> {
+        bc.blocks().unwrap()[0].transactions[0].id.clone()
+    }
 
-        for vin in tx.vin.iter() {
-            let prev_tx_result = self.find_transaction(&vin.txid);
-            if let Ok(prev_tx) = prev_tx_result {
-                prev_txs.insert(hex::encode(&prev_tx.id), prev_tx);
-            }
+    /// An unsigned transaction spending output 0 of `txid`, owned by `from`.
+    fn spending(from: &Wallet, txid: &[u8]) -> Transaction {
+        Transaction {
+            vin: vec![TXInput {
+                txid: txid.to_vec(),
+                vout: 0,
+                signature: vec![],
+                pub_key: from.public_key.clone().into_bytes(),
+            }],
+            ..Default::default()
         }
+    }
+
+    /// A signed transaction paying `value` of output 0 of `txid` to `to`.
+    fn payment(
+        bc: &Blockchain,
+        from: &Wallet,
+        txid: &[u8],
+        to: &Wallet,
+        value: i64,
+    ) -> Transaction {
+        let mut tx = spending(from, txid);
+        tx.vout = vec![TXOutput::new(value, &to.get_address()).unwrap()];
+        tx.set_id();
+        bc.sign_transaction(&mut tx, from.secret_key().unwrap())
+            .unwrap();
+        tx
+    }
+
+    fn rejected(result: Result<()>, reason: &str) -> bool {
+        matches!(result, Err(Error::InvalidTransaction(r)) if r.ends_with(reason))
+    }
+
+    #[test]
+    fn bundles_cannot_be_moved_to_another_transaction() {
+        let (me, other) = (wallet(1), wallet(2));
+        let bc = chain(&me);
+
+        // Shield the genesis output, signing the bundle over the transaction's sighash.
+        let mut bound = spending(&me, &genesis_output(&bc));
+        let mut builder = Builder::new(Flags::from_parts(false, true), Frontier::default().root());
+        builder
+            .add_recipient(None, me.z_address().unwrap(), NoteValue::from_raw(10), None)
+            .unwrap();
+        let unauthorized: orchard::Bundle<_, i64> = builder.build(OsRng).unwrap();
+        let proven = unauthorized
+            .create_proof(&ProvingKey::build(), OsRng)
+            .unwrap();
+        let sighash = bound.shielded_sighash(&proven);
+        let bundle = proven.apply_signatures(OsRng, sighash, &[]).unwrap();
+        bound.bundle = (&bundle).into();
+        bound.set_id();
+        bc.sign_transaction(&mut bound, me.secret_key().unwrap())
+            .unwrap();
+        bc.check_transaction(&bound, &HashSet::new()).unwrap();
+
+        // Whoever controls the inputs cannot reuse the bundle in a transaction with
+        // other transparent parts.
+        let mut moved = bound.clone();
+        moved.vout = vec![TXOutput::new(0, &other.get_address()).unwrap()];
+        moved.set_id();
+        bc.sign_transaction(&mut moved, me.secret_key().unwrap())
+            .unwrap();
+        assert!(rejected(
+            bc.check_transaction(&moved, &HashSet::new()),
+            "invalid shielded bundle"
+        ));
+    }
 
-        tx.verify(&prev_txs)
+    #[test]
+    fn outputs_are_spent_at_most_once_and_in_full() {
+        let (me, other) = (wallet(1), wallet(2));
+        let mut bc = chain(&me);
+        let genesis = genesis_output(&bc);
+        let pay = payment(&bc, &me, &genesis, &other, 10);
+        let again = payment(&bc, &me, &genesis, &me, 10);
+
+        assert!(rejected(
+            bc.mine_block(vec![payment(&bc, &me, &genesis, &other, 11)]),
+            "outputs exceed inputs"
+        ));
+        assert!(rejected(
+            bc.mine_block(vec![pay.clone(), again.clone()]),
+            "spends an output that is already spent"
+        ));
+        bc.mine_block(vec![pay]).unwrap();
+        assert!(rejected(
+            bc.mine_block(vec![again]),
+            "spends an output that is already spent"
+        ));
+        assert_eq!(bc.blocks().unwrap().len(), 2);
     }
 }
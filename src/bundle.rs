@@ -1,27 +1,51 @@
-use orchard::bundle::Authorization;
+use nonempty::NonEmpty;
+use orchard::bundle::{Authorization, Flags};
+use orchard::circuit::Proof;
+use orchard::note::{ExtractedNoteCommitment, Nullifier, TransmittedNoteCiphertext};
+use orchard::primitives::redpallas::{self, SpendAuth};
+use orchard::tree::Anchor;
+use orchard::value::ValueCommitment;
 use orchard::{bundle::Authorized, Action as oAction};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fmt;
 
+const EFFECTS_PERSONALIZATION: &[u8] = b"tinyzcash_OrchEff";
+const AUTH_PERSONALIZATION: &[u8] = b"tinyzcash_OrchAuth";
+
+/// A bundle in the form stored on chain. Fields are kept as raw bytes, hex encoded when
+/// serialized, and only parsed into curve points when the bundle is rebuilt, so a
+/// malformed bundle can be read and hashed, and is rejected on validation.
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct Bundle {
     actions: Vec<Action>,
     flags: u8,
     value_balance: i64,
-    anchor: String,
-    proof: String,
-    binding_sig: String,
+    #[serde(with = "hex_bytes")]
+    anchor: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    proof: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    binding_sig: Vec<u8>,
 }
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Action {
-    nullifier: String,
-    rk: String,
-    cmx: String,
-    out_ciphertext: String,
-    ephemeral_key: String,
-    enc_ciphertext: String,
-    cv: String,
-    spend_auth_sig: String,
+    #[serde(with = "hex_bytes")]
+    nullifier: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    rk: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    cmx: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    out_ciphertext: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    ephemeral_key: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    enc_ciphertext: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    cv: Vec<u8>,
+    #[serde(with = "hex_bytes")]
+    spend_auth_sig: Vec<u8>,
 }
 
 impl From<&oAction<<Authorized as Authorization>::SpendAuth>> for Action {
@@ -29,14 +53,14 @@ impl From<&oAction<<Authorized as Authorization>::SpendAuth>> for Action {
         let rk: [u8; 32] = a.rk().into();
         let sig: [u8; 64] = a.authorization().into();
         Action {
-            nullifier: hex::encode(a.nullifier().to_bytes()),
-            rk: hex::encode(rk),
-            cmx: hex::encode(a.cmx().to_bytes()),
-            out_ciphertext: hex::encode(a.encrypted_note().out_ciphertext),
-            ephemeral_key: hex::encode(a.encrypted_note().epk_bytes),
-            enc_ciphertext: hex::encode(a.encrypted_note().enc_ciphertext),
-            cv: hex::encode(a.cv_net().to_bytes()),
-            spend_auth_sig: hex::encode(sig),
+            nullifier: a.nullifier().to_bytes().to_vec(),
+            rk: rk.to_vec(),
+            cmx: a.cmx().to_bytes().to_vec(),
+            out_ciphertext: a.encrypted_note().out_ciphertext.to_vec(),
+            ephemeral_key: a.encrypted_note().epk_bytes.to_vec(),
+            enc_ciphertext: a.encrypted_note().enc_ciphertext.to_vec(),
+            cv: a.cv_net().to_bytes().to_vec(),
+            spend_auth_sig: sig.to_vec(),
         }
     }
 }
@@ -49,23 +73,161 @@ impl From<&orchard::Bundle<Authorized, i64>> for Bundle {
             actions: b.actions().iter().map(Action::from).collect(),
             flags: b.flags().to_byte(),
             value_balance: *b.value_balance(),
-            anchor: hex::encode(b.anchor().to_bytes()),
-            proof: hex::encode(b.authorization().proof().as_ref()),
-            binding_sig: hex::encode(sig),
+            anchor: b.anchor().to_bytes().to_vec(),
+            proof: b.authorization().proof().as_ref().to_vec(),
+            binding_sig: sig.to_vec(),
+        }
+    }
+}
+
+impl Action {
+    /// Rebuilds the orchard action, or `None` if any field is malformed.
+    pub fn to_orchard(&self) -> Option<oAction<redpallas::Signature<SpendAuth>>> {
+        let rk: redpallas::VerificationKey<SpendAuth> = decode::<32>(&self.rk)?.try_into().ok()?;
+        let sig: [u8; 64] = decode(&self.spend_auth_sig)?;
+        Some(oAction::from_parts(
+            Option::from(Nullifier::from_bytes(&decode(&self.nullifier)?))?,
+            rk,
+            Option::from(ExtractedNoteCommitment::from_bytes(&decode(&self.cmx)?))?,
+            TransmittedNoteCiphertext {
+                epk_bytes: decode(&self.ephemeral_key)?,
+                enc_ciphertext: decode(&self.enc_ciphertext)?,
+                out_ciphertext: decode(&self.out_ciphertext)?,
+            },
+            Option::from(ValueCommitment::from_bytes(&decode(&self.cv)?))?,
+            sig.into(),
+        ))
+    }
+}
+
+impl Bundle {
+    /// Net value leaving the shielded pool; negative when value is shielded.
+    pub fn value_balance(&self) -> i64 {
+        self.value_balance
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Rebuilds the authorized orchard bundle so it can be verified or trial-decrypted,
+    /// or `None` if the bundle is empty or malformed.
+    pub fn to_orchard(&self) -> Option<orchard::Bundle<Authorized, i64>> {
+        let actions = self
+            .actions
+            .iter()
+            .map(Action::to_orchard)
+            .collect::<Option<Vec<_>>>()?;
+        let binding_sig: [u8; 64] = decode(&self.binding_sig)?;
+        Some(orchard::Bundle::from_parts(
+            NonEmpty::from_vec(actions)?,
+            Flags::from_byte(self.flags)?,
+            self.value_balance,
+            Option::from(Anchor::from_bytes(decode(&self.anchor)?))?,
+            Authorized::from_parts(Proof::new(self.proof.clone()), binding_sig.into()),
+        ))
+    }
+
+    /// Digest of the bundle's effecting data, i.e. everything except the proof and
+    /// signatures. Matches [`effects_digest`] computed over the original orchard bundle.
+    pub fn effects_digest(&self) -> [u8; 32] {
+        let actions = self.actions.iter().map(|a| {
+            [
+                &a.nullifier,
+                &a.rk,
+                &a.cmx,
+                &a.ephemeral_key,
+                &a.enc_ciphertext,
+                &a.out_ciphertext,
+                &a.cv,
+            ]
+            .map(|field| field.clone())
+        });
+        hash_effects(actions, self.flags, self.value_balance, &self.anchor)
+    }
+
+    /// Digest of the bundle's authorizing data: the proof and all signatures.
+    pub fn auth_digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(AUTH_PERSONALIZATION);
+        hasher.update(&self.proof);
+        for action in &self.actions {
+            hasher.update(&action.spend_auth_sig);
+        }
+        hasher.update(&self.binding_sig);
+        hasher.finalize().into()
+    }
+}
+
+/// Digest of an orchard bundle's effecting data, independent of its authorization state,
+/// so it can be computed before the bundle is proven and signed.
+pub fn effects_digest<A: Authorization>(b: &orchard::Bundle<A, i64>) -> [u8; 32] {
+    let actions = b.actions().iter().map(|a| {
+        let rk: [u8; 32] = a.rk().into();
+        [
+            a.nullifier().to_bytes().to_vec(),
+            rk.to_vec(),
+            a.cmx().to_bytes().to_vec(),
+            a.encrypted_note().epk_bytes.to_vec(),
+            a.encrypted_note().enc_ciphertext.to_vec(),
+            a.encrypted_note().out_ciphertext.to_vec(),
+            a.cv_net().to_bytes().to_vec(),
+        ]
+    });
+    hash_effects(
+        actions,
+        b.flags().to_byte(),
+        *b.value_balance(),
+        &b.anchor().to_bytes(),
+    )
+}
+
+fn decode<const N: usize>(field: &[u8]) -> Option<[u8; N]> {
+    field.try_into().ok()
+}
+
+/// Serializes bytes as a hex string.
+mod hex_bytes {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        hex::decode(String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+fn hash_effects(
+    actions: impl Iterator<Item = [Vec<u8>; 7]>,
+    flags: u8,
+    value_balance: i64,
+    anchor: &[u8],
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(EFFECTS_PERSONALIZATION);
+    for fields in actions {
+        for field in fields {
+            hasher.update(field);
         }
     }
+    hasher.update([flags]);
+    hasher.update(value_balance.to_le_bytes());
+    hasher.update(anchor);
+    hasher.finalize().into()
 }
 
 impl fmt::Display for Action {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "Nullifier: {}", self.nullifier)?;
-        writeln!(f, "rk: {}", self.rk)?;
-        writeln!(f, "cmx: {}", self.cmx)?;
-        writeln!(f, "OutCiphertext: {}", self.out_ciphertext)?;
-        writeln!(f, "EphemeralKey: {}", self.ephemeral_key)?;
-        writeln!(f, "EncCiphertext: {}", self.enc_ciphertext)?;
-        writeln!(f, "cv: {}", self.cv)?;
-        writeln!(f, "SpendAuthSig: {}", self.spend_auth_sig)
+        writeln!(f, "Nullifier: {}", hex::encode(&self.nullifier))?;
+        writeln!(f, "rk: {}", hex::encode(&self.rk))?;
+        writeln!(f, "cmx: {}", hex::encode(&self.cmx))?;
+        writeln!(f, "OutCiphertext: {}", hex::encode(&self.out_ciphertext))?;
+        writeln!(f, "EphemeralKey: {}", hex::encode(&self.ephemeral_key))?;
+        writeln!(f, "EncCiphertext: {}", hex::encode(&self.enc_ciphertext))?;
+        writeln!(f, "cv: {}", hex::encode(&self.cv))?;
+        writeln!(f, "SpendAuthSig: {}", hex::encode(&self.spend_auth_sig))
     }
 }
 
@@ -73,9 +235,9 @@ impl fmt::Display for Bundle {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         _ = writeln!(f, "Flags: {}", self.flags);
         _ = writeln!(f, "ValueBalance: {}", self.value_balance);
-        _ = writeln!(f, "Anchor: {}", self.anchor);
-        _ = writeln!(f, "Proof: {}", self.proof);
-        _ = writeln!(f, "BindingSignature: {}", self.binding_sig);
+        _ = writeln!(f, "Anchor: {}", hex::encode(&self.anchor));
+        _ = writeln!(f, "Proof: {}", hex::encode(&self.proof));
+        _ = writeln!(f, "BindingSignature: {}", hex::encode(&self.binding_sig));
 
         for (i, action) in self.actions.iter().enumerate() {
             _ = writeln!(f, "Action {}:\n{}", i, action);
@@ -1,18 +1,38 @@
-use std::{println, vec};
+use std::{collections::HashMap, fs, io::Write, println, vec};
 
-use crate::transaction::new_coinbase_tx;
-use crate::{
-    blockchain::Blockchain, deposit, pow::ProofOfWork, transaction, verify, wallet,
-    wallets::Wallets, withdraw, zsend,
-};
+use orchard::keys::Scope;
 use structopt::StructOpt;
+use tinyzcash::memo::{self, MEMO_SIZE};
+use tinyzcash::pczt::Pczt;
+use tinyzcash::sendmany::{self, Recipient};
+use tinyzcash::{
+    blockchain::Blockchain,
+    coinselect::{Outpoint, Selection, Strategy},
+    deposit, encoding,
+    error::{Error, Result},
+    history,
+    pow::ProofOfWork,
+    scanner,
+    transaction::{self, Transaction},
+    wallet::{self, Wallet},
+    wallets::{self, Wallets},
+    withdraw, zsend,
+};
 
+#[derive(StructOpt, Debug)]
+#[structopt(name = "tinyzcash", about = "A simple CLI application")]
 pub struct Cli {
+    #[structopt(
+        long,
+        global = true,
+        help = "use the named wallet file wallets/<name>.dat"
+    )]
+    pub wallet: Option<String>,
+    #[structopt(subcommand)]
     pub cmd: Command,
 }
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "tinyzcash", about = "A simple CLI application")]
 pub enum Command {
     #[structopt(name = "createblockchain", about = "createblockchain")]
     CreateBlockChain {
@@ -20,15 +40,68 @@ pub enum Command {
         address: String,
     },
 
-    #[structopt(name = "createwallet", about = "create wallet")]
+    #[structopt(
+        name = "createwallet",
+        about = "add an account to the wallet, derived from its mnemonic"
+    )]
     Createwallet,
 
+    #[structopt(
+        name = "listaccounts",
+        about = "list the accounts with their addresses and balances"
+    )]
+    ListAccounts,
+
+    #[structopt(name = "restorewallet", about = "restore accounts from a mnemonic")]
+    RestoreWallet {
+        #[structopt(help = "BIP-39 mnemonic, quoted")]
+        mnemonic: String,
+        #[structopt(long, default_value = "0", help = "height to start scanning from")]
+        birthday: u64,
+        #[structopt(long, default_value = "1", help = "number of accounts to restore")]
+        accounts: u32,
+    },
+
+    #[structopt(
+        name = "encryptwallet",
+        about = "encrypt the wallet file with a passphrase"
+    )]
+    EncryptWallet {
+        #[structopt(help = "passphrase")]
+        passphrase: String,
+    },
+
+    #[structopt(
+        name = "walletpassphrase",
+        about = "unlock the wallet file for a number of seconds"
+    )]
+    WalletPassphrase {
+        #[structopt(help = "passphrase")]
+        passphrase: String,
+        #[structopt(help = "seconds to stay unlocked")]
+        timeout: u64,
+    },
+
+    #[structopt(name = "walletlock", about = "lock the wallet file")]
+    WalletLock,
+
     #[structopt(name = "printchain", about = "print the chain")]
     PrintChain,
 
     #[structopt(name = "listaddress", about = "listAddress")]
     ListAddress,
 
+    #[structopt(
+        name = "setlabel",
+        about = "label an address; the label can then stand in for it"
+    )]
+    SetLabel {
+        #[structopt(help = "an owned or external address")]
+        address: String,
+        #[structopt(help = "label, or empty to remove it")]
+        label: String,
+    },
+
     #[structopt(name = "send", about = "send")]
     Send {
         #[structopt(help = "from")]
@@ -37,6 +110,47 @@ pub enum Command {
         to: String,
         #[structopt(help = "amount")]
         amount: i64,
+        #[structopt(
+            long,
+            default_value = "bnb",
+            help = "coin selection strategy: largest, bnb or random"
+        )]
+        strategy: Strategy,
+        #[structopt(long = "utxo", help = "spend this output, as txid:vout; repeatable")]
+        utxos: Vec<Outpoint>,
+    },
+
+    #[structopt(
+        name = "listunspent",
+        about = "list the unspent transparent outputs of the wallet"
+    )]
+    ListUnspent {
+        #[structopt(
+            long,
+            default_value = "1",
+            help = "confirmations needed to list an output"
+        )]
+        minconf: u64,
+    },
+
+    #[structopt(
+        name = "lockunspent",
+        about = "exclude outputs from coin selection, or release them"
+    )]
+    LockUnspent {
+        #[structopt(help = "outputs as txid:vout", required = true)]
+        outpoints: Vec<Outpoint>,
+        #[structopt(long, help = "release the outputs instead")]
+        unlock: bool,
+    },
+
+    #[structopt(
+        name = "getnewzaddress",
+        about = "issue a new diversified shielded address"
+    )]
+    GetNewZAddress {
+        #[structopt(help = "an address of the wallet")]
+        address: String,
     },
 
     #[structopt(name = "getbalance", about = "getbalance")]
@@ -44,12 +158,55 @@ pub enum Command {
         #[structopt(help = "Address")]
         address: String,
     },
-    #[structopt(name = "deposit", about = "deposit")]
-    Deposit {
+    #[structopt(
+        name = "z_getbalance",
+        about = "get the balance of a transparent or shielded address"
+    )]
+    ZGetBalance {
         #[structopt(help = "address")]
         address: String,
-        #[structopt(help = "amount")]
-        amount: u64,
+        #[structopt(
+            long,
+            default_value = "1",
+            help = "confirmations needed to count funds"
+        )]
+        minconf: u64,
+    },
+    #[structopt(
+        name = "gettotalbalance",
+        about = "get the wallet's balance in each pool"
+    )]
+    GetTotalBalance {
+        #[structopt(
+            long,
+            default_value = "1",
+            help = "confirmations needed to count funds"
+        )]
+        minconf: u64,
+    },
+    #[structopt(
+        name = "listtransactions",
+        about = "list the transactions of the wallet or one of its addresses"
+    )]
+    ListTransactions {
+        #[structopt(help = "limit to the account owning this address")]
+        address: Option<String>,
+    },
+    #[structopt(
+        name = "deposit",
+        about = "shield transparent funds into an Orchard address"
+    )]
+    Deposit {
+        #[structopt(help = "transparent address to spend from")]
+        address: String,
+        #[structopt(help = "amount", required_unless = "all")]
+        amount: Option<u64>,
+        #[structopt(long, conflicts_with = "amount", help = "shield every unlocked output")]
+        all: bool,
+        #[structopt(long, help = "shielded address to pay; defaults to the sender's own")]
+        to: Option<String>,
+        #[structopt(long, help = "memo text, or hex prefixed with 0x", parse(try_from_str = memo::parse_memo))]
+        memo: Option<[u8; MEMO_SIZE]>,
     },
     #[structopt(name = "zsend", about = "zsend")]
     Zsend {
@@ -57,57 +214,381 @@ pub enum Command {
         from: String,
         #[structopt(help = "to")]
         to: String,
+        #[structopt(help = "amount")]
+        amount: u64,
+        #[structopt(long, help = "memo text, or hex prefixed with 0x", parse(try_from_str = memo::parse_memo))]
+        memo: Option<[u8; MEMO_SIZE]>,
     },
-    #[structopt(name = "withdraw", about = "withdraw")]
-    Withdraw {
-        #[structopt(help = "address")]
+    #[structopt(name = "z_sendmany", about = "z_sendmany")]
+    ZSendMany {
+        #[structopt(help = "from")]
+        from: String,
+        #[structopt(help = "recipients as address:amount[:memo]", required = true)]
+        recipients: Vec<Recipient>,
+    },
+    #[structopt(
+        name = "pczt_create",
+        about = "plan a z_sendmany-style transaction for offline signing"
+    )]
+    PcztCreate {
+        #[structopt(help = "from")]
+        from: String,
+        #[structopt(help = "recipients as address:amount[:memo]", required = true)]
+        recipients: Vec<Recipient>,
+        #[structopt(long, help = "where to write the partial transaction")]
+        out: String,
+    },
+    #[structopt(
+        name = "pczt_sign",
+        about = "add this wallet's signatures to a partial transaction"
+    )]
+    PcztSign {
+        #[structopt(help = "partial transaction path")]
+        path: String,
+    },
+    #[structopt(name = "pczt_prove", about = "prove a signed partial transaction")]
+    PcztProve {
+        #[structopt(help = "partial transaction path")]
+        path: String,
+    },
+    #[structopt(
+        name = "pczt_send",
+        about = "broadcast a proven and signed partial transaction"
+    )]
+    PcztSend {
+        #[structopt(help = "partial transaction path")]
+        path: String,
+    },
+    #[structopt(
+        name = "z_listreceived",
+        about = "list notes received by a shielded address"
+    )]
+    ZListReceived {
+        #[structopt(help = "zaddress")]
+        address: String,
+    },
+    #[structopt(
+        name = "z_history",
+        about = "list shielded payments sent and received by an address"
+    )]
+    ZHistory {
+        #[structopt(help = "zaddress")]
         address: String,
     },
+    #[structopt(
+        name = "exportviewingkey",
+        about = "export the viewing key of a shielded address"
+    )]
+    ExportViewingKey {
+        #[structopt(help = "zaddress")]
+        address: String,
+        #[structopt(long, help = "export only the incoming viewing key")]
+        ivk: bool,
+    },
+    #[structopt(
+        name = "importviewingkey",
+        about = "import a viewing key as a watch-only account"
+    )]
+    ImportViewingKey {
+        #[structopt(help = "Bech32m full or incoming viewing key")]
+        key: String,
+        #[structopt(long, default_value = "0", help = "height to start scanning from")]
+        rescan_from: u64,
+    },
+    #[structopt(name = "importprivkey", about = "import a transparent private key")]
+    ImportPrivKey {
+        #[structopt(help = "hex private key")]
+        key: String,
+        #[structopt(long, default_value = "0", help = "height to start scanning from")]
+        rescan_from: u64,
+    },
+    #[structopt(name = "importspendingkey", about = "import an Orchard spending key")]
+    ImportSpendingKey {
+        #[structopt(help = "Bech32m spending key")]
+        key: String,
+        #[structopt(long, default_value = "0", help = "height to start scanning from")]
+        rescan_from: u64,
+    },
+    #[structopt(
+        name = "backupwallet",
+        about = "copy the wallet file, encrypted if it is"
+    )]
+    BackupWallet {
+        #[structopt(help = "destination path")]
+        path: String,
+    },
+    #[structopt(
+        name = "dumpwallet",
+        about = "write all keys with their birthdays to a text file"
+    )]
+    DumpWallet {
+        #[structopt(help = "destination path")]
+        path: String,
+    },
+    #[structopt(
+        name = "importwallet",
+        about = "import the keys of a file written by dumpwallet"
+    )]
+    ImportWallet {
+        #[structopt(help = "dump path")]
+        path: String,
+    },
+    #[structopt(
+        name = "withdraw",
+        about = "unshield an amount to a transparent address"
+    )]
+    Withdraw {
+        #[structopt(help = "shielded address to spend from")]
+        from: String,
+        #[structopt(help = "transparent address to pay")]
+        to: String,
+        #[structopt(help = "amount")]
+        amount: u64,
+    },
 }
 
 impl Cli {
-    pub fn run(&mut self) {
+    pub fn run(&mut self) -> Result<()> {
+        if let Some(name) = &self.wallet {
+            wallets::select_wallet(name)?;
+        }
+        let _lock = wallets::acquire_lock()?;
+        // A previous run mined a block but died before syncing the wallets with it.
+        if wallets::chain_update_pending() {
+            scanner::sync(&Blockchain::open()?)?;
+        }
         match &self.cmd {
             Command::CreateBlockChain { address } => self.create_blockchain(address.clone()),
             Command::Createwallet => self.create_wallet(),
+            Command::ListAccounts => self.list_accounts(),
+            Command::RestoreWallet {
+                mnemonic,
+                birthday,
+                accounts,
+            } => self.restore_wallet(mnemonic, *birthday, *accounts),
+            Command::EncryptWallet { passphrase } => self.encrypt_wallet(passphrase),
+            Command::WalletPassphrase {
+                passphrase,
+                timeout,
+            } => self.wallet_passphrase(passphrase, *timeout),
+            Command::WalletLock => self.wallet_lock(),
             Command::PrintChain => self.print_chain(),
             Command::ListAddress => self.list_address(),
-            Command::Send { from, to, amount } => self.send(from.clone(), to.clone(), *amount),
+            Command::SetLabel { address, label } => self.set_label(address, label),
+            Command::Send {
+                from,
+                to,
+                amount,
+                strategy,
+                utxos,
+            } => {
+                let selection = Selection {
+                    strategy: *strategy,
+                    pinned: utxos.clone(),
+                };
+                self.send(resolve(from)?, resolve(to)?, *amount, &selection)
+            }
+            Command::ListUnspent { minconf } => self.list_unspent(*minconf),
+            Command::LockUnspent { outpoints, unlock } => self.lock_unspent(outpoints, *unlock),
+            Command::GetNewZAddress { address } => self.get_new_z_address(address),
             Command::Getbalance { address } => self.get_balance(address.clone()),
-            Command::Deposit { address, amount } => self.deposit(address.clone(), *amount),
-            Command::Zsend { from, to } => self.zsend(from.clone(), to.clone()),
-            Command::Withdraw { address } => self.withdraw(address.clone()),
+            Command::ZGetBalance { address, minconf } => self.z_get_balance(address, *minconf),
+            Command::GetTotalBalance { minconf } => self.get_total_balance(*minconf),
+            Command::ListTransactions { address } => self.list_transactions(address.as_deref()),
+            Command::Deposit {
+                address,
+                amount,
+                all,
+                to,
+                memo,
+            } => {
+                let to = to.as_deref().map(resolve).transpose()?;
+                let amount = if *all { None } else { *amount };
+                self.deposit(resolve(address)?, to, amount, *memo)
+            }
+            Command::Zsend {
+                from,
+                to,
+                amount,
+                memo,
+            } => self.zsend(resolve(from)?, resolve(to)?, *amount, *memo),
+            Command::ZSendMany { from, recipients } => self.z_sendmany(resolve(from)?, recipients),
+            Command::PcztCreate {
+                from,
+                recipients,
+                out,
+            } => self.pczt_create(&resolve(from)?, recipients, out),
+            Command::PcztSign { path } => self.pczt_sign(path),
+            Command::PcztProve { path } => self.pczt_prove(path),
+            Command::PcztSend { path } => self.pczt_send(path),
+            Command::ZListReceived { address } => self.z_list_received(address),
+            Command::ZHistory { address } => self.z_history(address),
+            Command::ExportViewingKey { address, ivk } => self.export_viewing_key(address, *ivk),
+            Command::ImportViewingKey { key, rescan_from } => {
+                self.import_viewing_key(key, *rescan_from)
+            }
+            Command::ImportPrivKey { key, rescan_from } => self.import_priv_key(key, *rescan_from),
+            Command::ImportSpendingKey { key, rescan_from } => {
+                self.import_spending_key(key, *rescan_from)
+            }
+            Command::BackupWallet { path } => self.backup_wallet(path),
+            Command::DumpWallet { path } => self.dump_wallet(path),
+            Command::ImportWallet { path } => self.import_wallet(path),
+            Command::Withdraw { from, to, amount } => {
+                self.withdraw(resolve(from)?, resolve(to)?, *amount)
+            }
         }
     }
 
-    fn create_blockchain(&self, address: String) {
-        Blockchain::new(address.as_str());
+    fn create_blockchain(&self, address: String) -> Result<()> {
+        if !wallet::validate_address(&address) {
+            return Err(Error::InvalidAddress(address));
+        }
+        match Blockchain::open() {
+            Ok(_) => {}
+            Err(Error::NoBlockchain) => {
+                println!("Mining the block");
+                let bc = Blockchain::new(&address)?;
+                println!("{}\n", hex::encode(&bc.tip));
+            }
+            Err(e) => return Err(e),
+        }
         println!("Done");
+        Ok(())
+    }
+
+    fn create_wallet(&self) -> Result<()> {
+        let mut w = Wallets::new()?;
+        let first = w.mnemonic().is_empty();
+        let address = w.create_wallet(Blockchain::height()?)?;
+        w.save_to_file()?;
+        println!("addr:{}", address);
+        if first {
+            println!(
+                "Write down this mnemonic; it restores every account created with createwallet, \
+                 but not imported keys:"
+            );
+            println!("{}", w.mnemonic());
+        }
+        Ok(())
+    }
+
+    fn restore_wallet(&self, mnemonic: &str, birthday: u64, accounts: u32) -> Result<()> {
+        let mut w = Wallets::new()?;
+        let restored = w.restore_wallet(mnemonic, accounts, check_birthday(birthday)?)?;
+        self.finish_import(w, restored)
+    }
+
+    /// Saves the wallets after keys were added and scans the chain for them from their
+    /// birthdays, if there is a chain yet, then prints their addresses.
+    fn finish_import(&self, w: Wallets, addresses: Vec<String>) -> Result<()> {
+        w.save_to_file()?;
+        match Blockchain::open() {
+            Ok(bc) => scanner::sync(&bc)?,
+            Err(Error::NoBlockchain) => {}
+            Err(e) => return Err(e),
+        }
+        for address in addresses {
+            if wallet::validate_address(&address) {
+                println!("addr:{}", address);
+            } else {
+                println!("zaddr:{}", address);
+            }
+        }
+        Ok(())
+    }
+
+    fn backup_wallet(&self, path: &str) -> Result<()> {
+        Wallets::new()?.backup(path)?;
+        println!("Wallet backed up to {}", path);
+        Ok(())
+    }
+
+    fn dump_wallet(&self, path: &str) -> Result<()> {
+        let dump = Wallets::new()?.dump()?;
+        // The dump holds spending keys in the clear.
+        wallets::create_private_file(path)?.write_all(dump.as_bytes())?;
+        println!("Keys written to {}", path);
+        Ok(())
     }
 
-    fn create_wallet(&self) {
-        let mut w = Wallets::new();
-        w.create_wallet();
-        _ = w.save_to_file();
+    fn import_wallet(&self, path: &str) -> Result<()> {
+        let dump = fs::read_to_string(path)?;
+        let mut w = Wallets::new()?;
+        let imported = w.import_dump(&dump)?;
+        self.finish_import(w, imported)
     }
 
-    fn list_address(&self) {
-        let w = Wallets::new();
-        let address = w.get_addresses();
-        for a in &address {
-            println!("addr:{:}", a);
+    fn import_priv_key(&self, key: &str, rescan_from: u64) -> Result<()> {
+        let mut w = Wallets::new()?;
+        let imported = w.import_priv_key(key, check_birthday(rescan_from)?)?;
+        self.finish_import(w, vec![imported])
+    }
+
+    fn import_spending_key(&self, key: &str, rescan_from: u64) -> Result<()> {
+        let mut w = Wallets::new()?;
+        let imported = w.import_spending_key(key, check_birthday(rescan_from)?)?;
+        self.finish_import(w, vec![imported])
+    }
+
+    fn list_address(&self) -> Result<()> {
+        let w = Wallets::new()?;
+        for a in w.get_addresses() {
+            println!("addr:{}{}", a, label_field(&w, &a));
+        }
+        for a in w.get_z_addresses() {
+            println!("zaddr:{}{}", a, label_field(&w, &a));
         }
-        let address = w.get_z_addresses();
-        for a in &address {
-            println!("zaddr:{:}", a);
+        for (a, label) in w.contacts() {
+            println!("contact:{} label:{}", a, label);
         }
+        Ok(())
+    }
+
+    fn set_label(&self, address: &str, label: &str) -> Result<()> {
+        let mut w = Wallets::new()?;
+        w.set_label(address, label)?;
+        w.save_to_file()?;
+        println!("Success!");
+        Ok(())
+    }
+
+    fn get_new_z_address(&self, address: &str) -> Result<()> {
+        let mut w = Wallets::new()?;
+        let zaddr = w.get_new_z_address(address)?;
+        w.save_to_file()?;
+        println!("zaddr:{}", zaddr);
+        Ok(())
     }
 
-    fn print_chain(&self) {
-        let bc = Blockchain::new("");
-        let mut bci = bc.iterator();
+    fn encrypt_wallet(&self, passphrase: &str) -> Result<()> {
+        let mut w = Wallets::new()?;
+        w.encrypt_wallet(passphrase)?;
+        w.save_to_file()?;
+        println!("Wallet encrypted; it is now locked.");
+        Ok(())
+    }
 
-        while let Some(block) = bci.next() {
+    fn wallet_passphrase(&self, passphrase: &str, timeout: u64) -> Result<()> {
+        let mut w = Wallets::new()?;
+        w.unlock(passphrase, timeout)?;
+        w.save_to_file()?;
+        println!("Wallet unlocked for {} seconds.", timeout);
+        Ok(())
+    }
+
+    fn wallet_lock(&self) -> Result<()> {
+        let mut w = Wallets::new()?;
+        w.lock()?;
+        w.save_to_file()?;
+        println!("Wallet locked.");
+        Ok(())
+    }
+
+    fn print_chain(&self) -> Result<()> {
+        let bc = Blockchain::open()?;
+        for block in bc.iterator() {
+            let block = block?;
             println!("Prev hash: {:}", hex::encode(&block.prev_block_hash));
             println!("Hash: {:}", hex::encode(&block.hash));
             let pow = ProofOfWork::new(&block);
@@ -118,78 +599,497 @@ impl Cli {
             }
             println!();
         }
+        Ok(())
     }
 
-    fn send(&self, from: String, to: String, amount: i64) {
+    fn send(&self, from: String, to: String, amount: i64, selection: &Selection) -> Result<()> {
         if !wallet::validate_address(&from) {
-            panic!("Sender address is not valid")
+            return Err(Error::InvalidAddress(from));
         }
         if !wallet::validate_address(&to) {
-            panic!("Recipient address is not valid")
+            return Err(Error::InvalidAddress(to));
+        }
+        let mut bc = Blockchain::open()?;
+        let tx = transaction::new_utxo_transaction(from, to, amount, selection, &bc)?;
+        mine(&mut bc, vec![tx])?;
+        println!("Success!");
+        Ok(())
+    }
+
+    fn list_unspent(&self, minconf: u64) -> Result<()> {
+        let bc = Blockchain::open()?;
+        let tip = bc.blocks()?.len() as u64;
+        let wallets = Wallets::new()?;
+        for address in wallets.get_addresses() {
+            let wallet = wallets
+                .get_wallet(&address)
+                .ok_or_else(|| Error::UnknownAddress(address.clone()))?;
+            let pub_key_hash = wallet::hash_pub_key(wallet.public_key.as_bytes());
+            for utxo in bc.find_utxos(&pub_key_hash)? {
+                let confirmations = tip - utxo.height;
+                if confirmations < minconf {
+                    continue;
+                }
+                println!(
+                    "txid:{} vout:{} address:{} value:{} confirmations:{} locked:{}{}",
+                    utxo.outpoint.txid,
+                    utxo.outpoint.vout,
+                    address,
+                    utxo.value,
+                    confirmations,
+                    wallets.is_locked_output(&utxo.outpoint),
+                    label_field(&wallets, &address)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn lock_unspent(&self, outpoints: &[Outpoint], unlock: bool) -> Result<()> {
+        let bc = Blockchain::open()?;
+        let mut wallets = Wallets::new()?;
+        let mut unspent: Vec<Outpoint> = vec![];
+        for address in wallets.get_addresses() {
+            if let Some(wallet) = wallets.get_wallet(&address) {
+                let pub_key_hash = wallet::hash_pub_key(wallet.public_key.as_bytes());
+                unspent.extend(
+                    bc.find_utxos(&pub_key_hash)?
+                        .into_iter()
+                        .map(|u| u.outpoint),
+                );
+            }
         }
-        let mut bc = Blockchain::new(&from);
-        let tx = transaction::new_utxo_transaction(from, to, amount, &bc);
-        bc.mine_block(vec![tx]);
+        for outpoint in outpoints {
+            if unlock && !wallets.unlock_output(outpoint) {
+                return Err(Error::InvalidInput(format!("{} is not locked", outpoint)));
+            }
+            if !unlock {
+                if !unspent.contains(outpoint) {
+                    return Err(Error::InvalidInput(format!(
+                        "{} is not an unspent output of the wallet",
+                        outpoint
+                    )));
+                }
+                wallets.lock_output(outpoint);
+            }
+        }
+        wallets.save_to_file()?;
         println!("Success!");
+        Ok(())
     }
 
-    fn get_balance(&self, address: String) {
+    fn get_balance(&self, address: String) -> Result<()> {
+        if let Some(balance) = self.z_balance(&address)? {
+            let w = Wallets::new()?;
+            println!(
+                "Balance of {}: {}{}",
+                labelled(&w, &address),
+                balance,
+                w.get_z_wallet(&address).map_or("", spend_status)
+            );
+            return Ok(());
+        }
         if !wallet::validate_address(&address) {
-            panic!("address is not valid")
-        }
-        let bc = Blockchain::new(&address);
-        let mut balance = 0;
-        let mut pub_key_hash = bs58::decode(address.clone()).into_vec().unwrap();
-        pub_key_hash = pub_key_hash[1..pub_key_hash.len() - wallet::CHECKSUM_LENGTH].to_vec();
-        let utxos = bc.find_utxo(&pub_key_hash);
-        for out in utxos {
-            balance += out.value;
-        }
-        println!("Balance of '{}': {}", address, balance);
-    }
-
-    fn deposit(&self, address: String, amount: u64) {
-        let mut bc = Blockchain::new(&address);
-        let mut tx = transaction::new_utxo_transaction(
-            address.clone(),
-            "11111111111111111111".to_string(),
-            amount as i64,
-            &bc,
+            return Err(Error::InvalidAddress(address));
+        }
+        let bc = Blockchain::open()?;
+        let balance = transparent_balance(&bc, &address)?;
+        println!(
+            "Balance of {}: {}",
+            labelled(&Wallets::new()?, &address),
+            balance
+        );
+        Ok(())
+    }
+
+    fn z_get_balance(&self, address: &str, minconf: u64) -> Result<()> {
+        let bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
+
+        let w = Wallets::new()?;
+        let blocks = bc.blocks()?;
+        let wallet = w
+            .get_wallet(address)
+            .or_else(|| w.get_z_wallet(address))
+            .ok_or_else(|| Error::UnknownAddress(address.to_string()))?;
+        // Only one pool holds funds of a single address.
+        let b = history::address_balance(&blocks, wallet, address, minconf)?;
+        println!(
+            "Balance of {}: {} (unconfirmed: {}){}",
+            labelled(&w, address),
+            b.total(),
+            b.transparent_unconfirmed + b.shielded_unconfirmed,
+            spend_status(wallet)
+        );
+        Ok(())
+    }
+
+    fn get_total_balance(&self, minconf: u64) -> Result<()> {
+        let bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
+
+        let w = Wallets::new()?;
+        let b = history::balance(&bc.blocks()?, &w.all(), minconf);
+        println!(
+            "transparent:{} private:{} total:{}",
+            b.transparent,
+            b.shielded,
+            b.total()
+        );
+        println!(
+            "unconfirmed transparent:{} private:{}",
+            b.transparent_unconfirmed, b.shielded_unconfirmed
         );
+        Ok(())
+    }
+
+    fn list_transactions(&self, address: Option<&str>) -> Result<()> {
+        let bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
+
+        let w = Wallets::new()?;
+        let wallets = match address {
+            Some(address) => match w.get_wallet(address).or_else(|| w.get_z_wallet(address)) {
+                Some(wallet) => vec![wallet],
+                None => return Err(Error::UnknownAddress(address.to_string())),
+            },
+            None => w.all(),
+        };
+        for entry in history::list_transactions(&bc.blocks()?, &wallets) {
+            let to: String = entry
+                .recipients
+                .iter()
+                .map(|to| format!(" to:{}{}", to, label_field(&w, to)))
+                .collect();
+            println!(
+                "height:{} confirmations:{} txid:{} {} amount:{} fee:{}{} memo:{}",
+                entry.height,
+                entry.confirmations,
+                entry.txid,
+                entry.direction,
+                entry.amount,
+                entry.fee.map_or("-".to_string(), |f| f.to_string()),
+                to,
+                entry.memo
+            );
+        }
+        Ok(())
+    }
+
+    fn list_accounts(&self) -> Result<()> {
+        let bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
+
+        let w = Wallets::new()?;
+        for (number, account) in w.all().into_iter().enumerate() {
+            let shielded: u64 = account
+                .notes
+                .iter()
+                .filter(|n| !n.is_spent())
+                .map(|n| n.value)
+                .sum();
+            let transparent = if account.has_transparent() {
+                let address = account.get_address();
+                format!(
+                    " addr:{} transparent:{}{}",
+                    address,
+                    transparent_balance(&bc, &address)?,
+                    label_field(&w, &address)
+                )
+            } else {
+                String::new()
+            };
+            println!(
+                "account:{}{} shielded:{}{}",
+                number,
+                transparent,
+                shielded,
+                spend_status(account)
+            );
+            for zaddr in w.z_addresses_of(number) {
+                println!("  zaddr:{}{}", zaddr, label_field(&w, &zaddr));
+            }
+        }
+        Ok(())
+    }
 
-        let bundle = deposit::deposit(&address, amount);
-        verify::verify_bundle(&bundle);
+    /// Sums the unspent notes of a shielded address, or `None` if it is not in the wallet.
+    /// This works for watch-only accounts too.
+    fn z_balance(&self, address: &str) -> Result<Option<u64>> {
+        if Wallets::new()?.get_z_wallet(address).is_none() {
+            return Ok(None);
+        }
+        scanner::sync(&Blockchain::open()?)?;
+        let wallets = Wallets::new()?;
+        Ok(wallets.get_z_wallet(address).map(|wallet| {
+            wallet
+                .notes
+                .iter()
+                .filter(|n| !n.is_spent())
+                .map(|n| n.value)
+                .sum()
+        }))
+    }
 
-        tx.bundle = (&bundle).into();
-        bc.mine_block(vec![tx]);
-        deposit::save_note(&bundle, &address);
+    fn deposit(
+        &self,
+        address: String,
+        to: Option<String>,
+        amount: Option<u64>,
+        memo: Option<[u8; MEMO_SIZE]>,
+    ) -> Result<()> {
+        let mut bc = Blockchain::open()?;
+        let tx = deposit::deposit(&address, to.as_deref(), amount, memo, &bc)?;
+        mine(&mut bc, vec![tx])?;
+        scanner::sync(&bc)?;
+        println!("Success!");
+        Ok(())
     }
 
-    fn zsend(&self, from: String, to: String) {
-        let mut bc = Blockchain::new(&from);
+    fn zsend(
+        &self,
+        from: String,
+        to: String,
+        amount: u64,
+        memo: Option<[u8; MEMO_SIZE]>,
+    ) -> Result<()> {
+        let mut bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
 
-        let bundle = zsend::zsend(&from, &to);
-        verify::verify_bundle(&bundle);
+        let bundle = zsend::zsend(&from, &to, amount, memo)?;
 
         let mut tx = transaction::Transaction {
             bundle: (&bundle).into(),
             ..Default::default()
         };
         tx.set_id();
-        bc.mine_block(vec![tx]);
-        zsend::save_note(&bundle, &from, &to);
+        mine(&mut bc, vec![tx])?;
+        scanner::sync(&bc)?;
+        println!("Success!");
+        Ok(())
+    }
+
+    fn z_sendmany(&self, from: String, recipients: &[Recipient]) -> Result<()> {
+        let mut bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
+
+        let tx = sendmany::z_sendmany(&from, recipients, &bc)?;
+        mine(&mut bc, vec![tx])?;
+        scanner::sync(&bc)?;
+        println!("Success!");
+        Ok(())
+    }
+
+    fn pczt_create(&self, from: &str, recipients: &[Recipient], out: &str) -> Result<()> {
+        let bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
+
+        Pczt::create(from, recipients, &bc)?.write(out)?;
+        println!("Partial transaction written to {}", out);
+        Ok(())
+    }
+
+    fn pczt_sign(&self, path: &str) -> Result<()> {
+        let mut pczt = Pczt::read(path)?;
+        let bc = Blockchain::open()?;
+        let summary = pczt.summary(&bc)?;
+        for (recipient, value) in &summary.outputs {
+            println!("pays {} to {}", value, recipient);
+        }
+        println!("fee: {}", summary.fee);
+        let added = pczt.sign(&Wallets::new()?, &bc)?;
+        pczt.write(path)?;
+        println!("Added {} signatures", added);
+        Ok(())
+    }
+
+    fn pczt_prove(&self, path: &str) -> Result<()> {
+        let mut pczt = Pczt::read(path)?;
+        pczt.prove()?;
+        pczt.write(path)?;
+        println!("Proof added to {}", path);
+        Ok(())
+    }
+
+    fn pczt_send(&self, path: &str) -> Result<()> {
+        let tx = Pczt::read(path)?.finalize()?;
+        let mut bc = Blockchain::open()?;
+        mine(&mut bc, vec![tx])?;
+        scanner::sync(&bc)?;
+        println!("Success!");
+        Ok(())
+    }
+
+    fn z_list_received(&self, address: &str) -> Result<()> {
+        let bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
+
+        let wallets = Wallets::new()?;
+        let wallet = wallets
+            .get_z_wallet(address)
+            .ok_or_else(|| Error::UnknownAddress(address.to_string()))?;
+        for note in &wallet.notes {
+            let memo = hex::decode(&note.memo).unwrap_or_default();
+            println!(
+                "txid:{} value:{} spent:{} memo:{}",
+                note.txid,
+                note.value,
+                note.is_spent(),
+                memo::display_memo(&memo)
+            );
+        }
+        Ok(())
+    }
+
+    fn z_history(&self, address: &str) -> Result<()> {
+        let bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
+
+        let wallets = Wallets::new()?;
+        let wallet = wallets
+            .get_z_wallet(address)
+            .ok_or_else(|| Error::UnknownAddress(address.to_string()))?;
+        let heights: HashMap<String, usize> = bc
+            .blocks()?
+            .iter()
+            .enumerate()
+            .map(|(height, b)| (hex::encode(&b.hash), height))
+            .collect();
+        // Change returning to the wallet is neither a payment sent nor one received.
+        let change = wallet
+            .fvk()
+            .map(|fvk| hex::encode(fvk.address_at(0u32, Scope::Internal).to_raw_address_bytes()));
+
+        let mut history = vec![];
+        for note in &wallet.notes {
+            if Some(&note.recipient) != change.as_ref() {
+                history.push((
+                    &note.block,
+                    "received",
+                    &note.txid,
+                    note.value,
+                    &note.memo,
+                    None,
+                ));
+            }
+        }
+        for sent in &wallet.sent {
+            let to = Some(&sent.recipient);
+            history.push((&sent.block, "sent", &sent.txid, sent.value, &sent.memo, to));
+        }
+        history.sort_by_key(|(block, ..)| heights.get(*block).copied());
+
+        for (block, kind, txid, value, memo, to) in history {
+            let memo = hex::decode(memo).unwrap_or_default();
+            let height = heights
+                .get(block)
+                .map_or("-".to_string(), |h| h.to_string());
+            let to =
+                to.and_then(|to| wallet::parse_z_address(to).ok())
+                    .map_or(String::new(), |to| {
+                        let to = encoding::encode_address(&to);
+                        format!(" to:{}{}", to, label_field(&wallets, &to))
+                    });
+            println!(
+                "height:{} {} txid:{} value:{}{} memo:{}",
+                height,
+                kind,
+                txid,
+                value,
+                to,
+                memo::display_memo(&memo)
+            );
+        }
+        Ok(())
+    }
+
+    fn export_viewing_key(&self, address: &str, ivk: bool) -> Result<()> {
+        let wallets = Wallets::new()?;
+        let wallet = wallets
+            .get_z_wallet(address)
+            .ok_or_else(|| Error::UnknownAddress(address.to_string()))?;
+        match (wallet.fvk(), ivk) {
+            (Some(fvk), false) => println!("{}", encoding::encode_fvk(&fvk)),
+            (Some(fvk), true) => println!("{}", encoding::encode_ivk(&fvk.to_ivk(Scope::External))),
+            // A wallet imported from an incoming viewing key can only give that back.
+            (None, _) => println!("{}", wallet.viewing_key),
+        }
+        Ok(())
+    }
+
+    fn import_viewing_key(&self, key: &str, rescan_from: u64) -> Result<()> {
+        let mut w = Wallets::new()?;
+        let imported = w.import_viewing_key(key, check_birthday(rescan_from)?)?;
+        self.finish_import(w, vec![imported])
+    }
+
+    fn withdraw(&self, from: String, to: String, amount: u64) -> Result<()> {
+        let mut bc = Blockchain::open()?;
+        scanner::sync(&bc)?;
+
+        let tx = withdraw::withdraw(&from, &to, amount)?;
+        mine(&mut bc, vec![tx])?;
+        scanner::sync(&bc)?;
+        println!("Success!");
+        Ok(())
+    }
+}
+
+/// Mines a block of `transactions`, printing its hash once the proof of work is found.
+/// The chain and the wallet file cannot be written together, so a pending update is
+/// recorded first; if the wallets are not synced with the block before this run ends,
+/// the next run syncs them before doing anything else.
+fn mine(bc: &mut Blockchain, transactions: Vec<Transaction>) -> Result<()> {
+    wallets::begin_chain_update()?;
+    println!("Mining the block");
+    bc.mine_block(transactions)?;
+    println!("{}\n", hex::encode(&bc.tip));
+    Ok(())
+}
+
+/// Rejects a height to scan from that is past the tip: blocks mined up to it later would
+/// never be scanned.
+fn check_birthday(height: u64) -> Result<u64> {
+    let tip = Blockchain::height()?;
+    if height > tip {
+        return Err(Error::InvalidInput(format!(
+            "height {} is past the chain tip at {}",
+            height, tip
+        )));
     }
-    fn withdraw(&self, address: String) {
-        let mut bc = Blockchain::new(&address);
+    Ok(height)
+}
+
+/// Resolves an address argument that may name an address book label.
+fn resolve(address: &str) -> Result<String> {
+    Ok(Wallets::new()?.resolve(address))
+}
 
-        let bundle = withdraw::withdraw(&address);
-        verify::verify_bundle(&bundle);
+/// A ` label:` field for output lines, empty when the address has no label.
+fn label_field(w: &Wallets, address: &str) -> String {
+    w.label(address)
+        .map_or(String::new(), |label| format!(" label:{}", label))
+}
+
+/// Quotes an address for messages, followed by its label if it has one.
+fn labelled(w: &Wallets, address: &str) -> String {
+    match w.label(address) {
+        Some(label) => format!("'{}' ({})", address, label),
+        None => format!("'{}'", address),
+    }
+}
 
-        let wallets = Wallets::new();
-        let wallet = wallets.get_z_wallet(&address).unwrap();
-        let mut tx = new_coinbase_tx(&wallet.get_address(), "withdraw", *bundle.value_balance());
-        tx.bundle = (&bundle).into();
-        bc.mine_block(vec![tx]);
-        withdraw::save_note(&address);
+/// Flags a balance that counts every note received, spent or not, because the account
+/// cannot detect spends.
+fn spend_status(wallet: &Wallet) -> &'static str {
+    if wallet.detects_spends() {
+        ""
+    } else {
+        " (unspent status unknown: imported from an incoming viewing key)"
     }
 }
+
+fn transparent_balance(bc: &Blockchain, address: &str) -> Result<i64> {
+    let pub_key_hash = wallet::decode_address(address)?;
+    Ok(bc.find_utxos(&pub_key_hash)?.iter().map(|u| u.value).sum())
+}
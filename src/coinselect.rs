@@ -0,0 +1,299 @@
+use rand::rngs::OsRng;
+use rand::seq::SliceRandom;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::Error;
+
+/// Upper bound on the branches branch-and-bound explores before giving up.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// A transaction output, written `txid:vout`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Outpoint {
+    pub txid: String,
+    pub vout: i32,
+}
+
+impl FromStr for Outpoint {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            |reason: String| Error::InvalidInput(format!("invalid outpoint '{}': {}", s, reason));
+        let (txid, vout) = s
+            .split_once(':')
+            .ok_or_else(|| invalid("expected txid:vout".to_string()))?;
+        if hex::decode(txid).is_err() {
+            return Err(invalid("txid is not hex".to_string()));
+        }
+        let vout = vout.parse().map_err(|e| invalid(format!("{}", e)))?;
+        // Outpoints are compared with the lowercase hex of transaction ids.
+        Ok(Outpoint {
+            txid: txid.to_ascii_lowercase(),
+            vout,
+        })
+    }
+}
+
+impl fmt::Display for Outpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
+}
+
+/// An unspent transparent output with the height of the block it was mined in.
+#[derive(Clone)]
+pub struct Utxo {
+    pub outpoint: Outpoint,
+    pub value: i64,
+    pub height: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// Spends the largest outputs first, using as few inputs as possible.
+    LargestFirst,
+    /// Searches for a set of outputs matching the amount exactly, so no change output
+    /// links the payment back to the sender; falls back to largest-first.
+    #[default]
+    BranchAndBound,
+    /// Spends outputs in random order, so the choice reveals nothing about the wallet.
+    Random,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest" => Ok(Strategy::LargestFirst),
+            "bnb" => Ok(Strategy::BranchAndBound),
+            "random" => Ok(Strategy::Random),
+            _ => Err(Error::InvalidInput(format!(
+                "unknown coin selection strategy '{}': use largest, bnb or random",
+                s
+            ))),
+        }
+    }
+}
+
+/// How a transparent send picks its inputs: `pinned` outputs are always spent, and the
+/// strategy adds more from the rest until the amount is covered.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub strategy: Strategy,
+    pub pinned: Vec<Outpoint>,
+}
+
+impl Selection {
+    /// Rejects an output pinned more than once, which would be spent twice.
+    pub fn check_pins(&self) -> Result<(), Error> {
+        let mut pins = HashSet::new();
+        match self.pinned.iter().find(|o| !pins.insert(*o)) {
+            Some(outpoint) => Err(Error::InvalidInput(format!(
+                "{} is given more than once",
+                outpoint
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Picks outputs from `utxos` worth at least `amount`, or `None` if they cannot cover it.
+pub fn select(utxos: &[Utxo], amount: i64, strategy: Strategy) -> Option<Vec<Utxo>> {
+    match strategy {
+        Strategy::LargestFirst => largest_first(utxos, amount),
+        Strategy::BranchAndBound => {
+            branch_and_bound(utxos, amount).or_else(|| largest_first(utxos, amount))
+        }
+        Strategy::Random => {
+            let mut shuffled = utxos.to_vec();
+            shuffled.shuffle(&mut OsRng);
+            accumulate(shuffled, amount)
+        }
+    }
+}
+
+fn largest_first(utxos: &[Utxo], amount: i64) -> Option<Vec<Utxo>> {
+    let mut sorted = utxos.to_vec();
+    sorted.sort_by_key(|u| Reverse(u.value));
+    accumulate(sorted, amount)
+}
+
+fn accumulate(utxos: Vec<Utxo>, amount: i64) -> Option<Vec<Utxo>> {
+    let mut selected = vec![];
+    let mut total = 0;
+    for utxo in utxos {
+        if total >= amount {
+            break;
+        }
+        total += utxo.value;
+        selected.push(utxo);
+    }
+    (total >= amount).then_some(selected)
+}
+
+/// Depth-first search over include/exclude decisions, largest outputs first, for a subset
+/// summing to exactly `amount`.
+fn branch_and_bound(utxos: &[Utxo], amount: i64) -> Option<Vec<Utxo>> {
+    let mut sorted = utxos.to_vec();
+    sorted.sort_by_key(|u| Reverse(u.value));
+    // remaining[i] is the value of sorted[i..], bounding what a branch can still add.
+    let mut remaining = vec![0; sorted.len() + 1];
+    for i in (0..sorted.len()).rev() {
+        remaining[i] = remaining[i + 1] + sorted[i].value;
+    }
+
+    let mut included = vec![false; sorted.len()];
+    let mut tries = 0;
+    if search(&sorted, &remaining, amount, 0, 0, &mut included, &mut tries) {
+        let selected = sorted
+            .into_iter()
+            .zip(included)
+            .filter(|(_, inc)| *inc)
+            .map(|(utxo, _)| utxo)
+            .collect();
+        return Some(selected);
+    }
+    None
+}
+
+fn search(
+    sorted: &[Utxo],
+    remaining: &[i64],
+    amount: i64,
+    depth: usize,
+    total: i64,
+    included: &mut [bool],
+    tries: &mut usize,
+) -> bool {
+    if total == amount {
+        return true;
+    }
+    *tries += 1;
+    if depth == sorted.len() || total > amount || total + remaining[depth] < amount {
+        return false;
+    }
+    if *tries > BNB_MAX_TRIES {
+        return false;
+    }
+    included[depth] = true;
+    let next = total + sorted[depth].value;
+    if search(sorted, remaining, amount, depth + 1, next, included, tries) {
+        return true;
+    }
+    included[depth] = false;
+    search(sorted, remaining, amount, depth + 1, total, included, tries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utxos(values: &[i64]) -> Vec<Utxo> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, &value)| Utxo {
+                outpoint: Outpoint {
+                    txid: format!("{:064x}", i),
+                    vout: 0,
+                },
+                value,
+                height: 0,
+            })
+            .collect()
+    }
+
+    fn values(selected: Option<Vec<Utxo>>) -> Vec<i64> {
+        let mut values: Vec<i64> = selected.unwrap().iter().map(|u| u.value).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn largest_first_spends_the_fewest_outputs() {
+        let selected = select(&utxos(&[1, 5, 3, 2]), 6, Strategy::LargestFirst);
+        assert_eq!(values(selected), vec![3, 5]);
+    }
+
+    #[test]
+    fn branch_and_bound_finds_an_exact_match() {
+        // Largest-first would take 5 and 4, leaving change.
+        let selected = select(&utxos(&[5, 4, 3, 1]), 7, Strategy::BranchAndBound);
+        assert_eq!(values(selected), vec![3, 4]);
+    }
+
+    #[test]
+    fn branch_and_bound_falls_back_to_largest_first() {
+        let selected = select(&utxos(&[5, 4]), 6, Strategy::BranchAndBound);
+        assert_eq!(values(selected), vec![4, 5]);
+    }
+
+    #[test]
+    fn random_covers_the_amount() {
+        for _ in 0..20 {
+            let selected = select(&utxos(&[1, 2, 3, 4]), 6, Strategy::Random).unwrap();
+            let total: i64 = selected.iter().map(|u| u.value).sum();
+            assert!(total >= 6);
+            // It stops as soon as the amount is covered.
+            assert!(total - selected.last().unwrap().value < 6);
+        }
+    }
+
+    #[test]
+    fn insufficient_funds_select_nothing() {
+        for strategy in [
+            Strategy::LargestFirst,
+            Strategy::BranchAndBound,
+            Strategy::Random,
+        ] {
+            assert!(select(&utxos(&[1, 2]), 4, strategy).is_none());
+            assert!(select(&[], 1, strategy).is_none());
+        }
+    }
+
+    #[test]
+    fn duplicate_pins_are_rejected() {
+        let outpoint: Outpoint = format!("{:064x}:1", 7).parse().unwrap();
+        let mut selection = Selection {
+            pinned: vec![outpoint.clone()],
+            ..Default::default()
+        };
+        assert!(selection.check_pins().is_ok());
+        selection.pinned.push(outpoint);
+        assert!(matches!(
+            selection.check_pins(),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn outpoints_round_trip() {
+        let outpoint: Outpoint = "ABcd01:3".parse().unwrap();
+        assert_eq!(outpoint.txid, "abcd01");
+        assert_eq!(outpoint.vout, 3);
+        assert_eq!(outpoint.to_string().parse::<Outpoint>().unwrap(), outpoint);
+
+        for invalid in ["abcd01", "xyz:0", "abcd01:one"] {
+            assert!(matches!(
+                invalid.parse::<Outpoint>(),
+                Err(Error::InvalidInput(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn strategies_parse() {
+        assert_eq!(
+            "largest".parse::<Strategy>().unwrap(),
+            Strategy::LargestFirst
+        );
+        assert_eq!("bnb".parse::<Strategy>().unwrap(), Strategy::BranchAndBound);
+        assert_eq!("random".parse::<Strategy>().unwrap(), Strategy::Random);
+        assert!("fifo".parse::<Strategy>().is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::error::{Error, Result};
+
+const NONCE_SIZE: usize = 12;
+
+/// Stretches a passphrase into a 32-byte encryption key with Argon2id.
+pub fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .unwrap();
+    key
+}
+
+pub fn random_salt() -> [u8; 16] {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+/// Encrypts with ChaCha20-Poly1305 under a fresh random nonce, returning the hex of the
+/// nonce followed by the ciphertext.
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> String {
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .unwrap();
+    hex::encode([nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Reverses [`encrypt`]. Fails when the key is wrong or the data was tampered with.
+pub fn decrypt(key: &[u8; 32], data: &str) -> Result<Vec<u8>> {
+    let data = hex::decode(data)
+        .map_err(|e| Error::InvalidInput(format!("invalid encrypted data: {}", e)))?;
+    if data.len() < NONCE_SIZE {
+        return Err(Error::InvalidInput(
+            "invalid encrypted data: too short".to_string(),
+        ));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key));
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| Error::InvalidInput("incorrect passphrase".to_string()))
+}
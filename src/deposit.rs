@@ -1,67 +1,98 @@
-use crate::{merkle, wallet, wallets::Wallets};
-use orchard::circuit::ProvingKey;
-use orchard::{
-    builder::Builder,
-    bundle::{Authorized, Flags},
-    keys::{FullViewingKey, PreparedIncomingViewingKey, Scope},
-    note_encryption::OrchardDomain,
-    value::NoteValue,
-    Bundle,
+use crate::{
+    blockchain::Blockchain,
+    coinselect::Selection,
+    error::{Error, Result},
+    memo::MEMO_SIZE,
+    merkle,
+    transaction::{self, Transaction},
+    wallet,
+    wallets::Wallets,
 };
+use orchard::circuit::ProvingKey;
+use orchard::{builder::Builder, bundle::Flags, keys::Scope, value::NoteValue};
 use rand::rngs::OsRng;
-use zcash_note_encryption::try_note_decryption;
-
-pub fn deposit(address: &str, value: u64) -> Bundle<Authorized, i64> {
-    let wallets = Wallets::new();
-    let wallet = wallets.get_wallet(address).unwrap();
-
-    let mut rng = OsRng;
-    let pk = ProvingKey::build();
 
-    let sk = wallet.sk();
-    let fvk = FullViewingKey::from(&sk);
-    let recipient = fvk.address_at(0u32, Scope::External);
+/// Shields funds of the transparent address `from` in a single transaction: its inputs
+/// fund an Orchard bundle whose negative value balance moves the value into the pool.
+/// `amount` of `None` shields every unlocked output of the address, leaving no change.
+/// The note goes to `to`, or to the sender's own shielded address when not given.
+pub fn deposit(
+    from: &str,
+    to: Option<&str>,
+    amount: Option<u64>,
+    memo: Option<[u8; MEMO_SIZE]>,
+    bc: &Blockchain,
+) -> Result<Transaction> {
+    let wallets = Wallets::new()?;
+    let wallet = wallets
+        .get_wallet(from)
+        .ok_or_else(|| Error::UnknownAddress(from.to_string()))?;
+    let private_key = wallet.secret_key()?;
 
-    // Create a shielding bundle.
-    let shielding_bundle: Bundle<_, i64> = {
-        // Use the empty tree.
-        let anchor = merkle::MERKLE.root(0).unwrap().into();
+    let recipient = match to {
+        Some(to) => wallet::parse_z_address(to)?,
+        None => wallet.z_address().ok_or_else(|| {
+            Error::MissingKey(format!("'{}' has no shielded address; give one", from))
+        })?,
+    };
 
-        let mut builder = Builder::new(Flags::from_parts(false, true), anchor);
-        assert_eq!(
-            builder.add_recipient(None, recipient, NoteValue::from_raw(value), None),
-            Ok(())
-        );
-        let unauthorized = builder.build(&mut rng).unwrap();
-        let sighash = unauthorized.commitment().into();
-        let proven = unauthorized.create_proof(&pk, &mut rng).unwrap();
-        proven.apply_signatures(rng, sighash, &[]).unwrap()
+    let (amount, selection) = match amount {
+        Some(amount) => (amount, Selection::default()),
+        None => {
+            let pub_key_hash = wallet::hash_pub_key(wallet.public_key.as_bytes());
+            let utxos: Vec<_> = bc
+                .find_utxos(&pub_key_hash)?
+                .into_iter()
+                .filter(|u| !wallets.is_locked_output(&u.outpoint))
+                .collect();
+            let selection = Selection {
+                pinned: utxos.iter().map(|u| u.outpoint.clone()).collect(),
+                ..Default::default()
+            };
+            let total = utxos
+                .iter()
+                .try_fold(0u64, |total, u| {
+                    total.checked_add(u64::try_from(u.value).ok()?)
+                })
+                .ok_or_else(|| Error::InvalidInput("output values out of range".to_string()))?;
+            (total, selection)
+        }
     };
-    shielding_bundle
-}
+    if amount == 0 {
+        return Err(Error::InvalidInput(format!(
+            "nothing to shield from '{}'",
+            from
+        )));
+    }
 
-pub fn save_note(bundle: &Bundle<Authorized, i64>, address: &str) {
-    let mut wallets = Wallets::new();
-    let wallet = wallets.get_wallet(address).unwrap();
-    let sk = wallet.sk();
-    let fvk = FullViewingKey::from(&sk);
-    let ivk = PreparedIncomingViewingKey::new(&fvk.to_ivk(Scope::External));
+    let value = i64::try_from(amount)
+        .map_err(|_| Error::InvalidInput(format!("amount {} is out of range", amount)))?;
+    let mut tx = transaction::new_funded_transaction(from, vec![], value, &selection, bc)?;
 
-    let (note, _, _) = bundle
-        .actions()
-        .iter()
-        .find_map(|action| {
-            let domain = OrchardDomain::for_action(action);
-            try_note_decryption(&domain, &ivk, action)
-        })
-        .unwrap();
-    let n = wallet::Note {
-        value: note.value().inner(),
-        rseed: *note.rseed().as_bytes(),
-        nf: note.rho().to_bytes(),
-    };
+    let mut rng = OsRng;
+    let pk = ProvingKey::build();
+    // Outputs are encrypted to the sender too, when it has shielded keys, so the scanner
+    // can recover what was sent.
+    let ovk = wallet.fvk().map(|fvk| fvk.to_ovk(Scope::External));
+
+    // Nothing is spent from the pool, so the empty tree's root serves as the anchor.
+    let anchor = merkle::Frontier::default().root();
+    let mut builder = Builder::new(Flags::from_parts(false, true), anchor);
+    builder
+        .add_recipient(ovk, recipient, NoteValue::from_raw(amount), memo)
+        .map_err(Error::bundle)?;
+    let unauthorized = builder.build(&mut rng).map_err(Error::bundle)?;
+    let proven = unauthorized
+        .create_proof(&pk, &mut rng)
+        .map_err(Error::bundle)?;
 
-    let wallet = wallets.get_mut_wallet(address);
-    wallet.notes.push(n);
-    _ = wallets.save_to_file();
+    // Both the inputs and the bundle are signed over the same sighash.
+    let sighash = tx.shielded_sighash(&proven);
+    let bundle = proven
+        .apply_signatures(rng, sighash, &[])
+        .map_err(Error::bundle)?;
+    tx.bundle = (&bundle).into();
+    tx.set_id();
+    bc.sign_transaction(&mut tx, private_key)?;
+    Ok(tx)
 }
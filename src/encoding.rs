@@ -0,0 +1,163 @@
+use bech32::{FromBase32, ToBase32, Variant};
+use orchard::keys::{FullViewingKey, IncomingViewingKey, SpendingKey};
+use orchard::Address;
+
+use crate::error::{Error, Result};
+
+pub const ADDRESS_HRP: &str = "tzo";
+pub const FVK_HRP: &str = "tzviewo";
+pub const IVK_HRP: &str = "tzivko";
+pub const SPENDING_KEY_HRP: &str = "tzsecreto";
+
+pub enum ViewingKey {
+    Full(FullViewingKey),
+    Incoming(IncomingViewingKey),
+}
+
+pub fn encode_address(address: &Address) -> String {
+    bech32::encode(
+        ADDRESS_HRP,
+        address.to_raw_address_bytes().to_base32(),
+        Variant::Bech32m,
+    )
+    .unwrap()
+}
+
+/// Decodes a Bech32m Orchard address.
+pub fn decode_address(s: &str) -> Result<Address> {
+    let invalid = || Error::InvalidAddress(s.to_string());
+    let (hrp, data) = decode(s).map_err(|_| invalid())?;
+    if hrp != ADDRESS_HRP {
+        return Err(invalid());
+    }
+    let bytes: [u8; 43] = data.try_into().map_err(|_| invalid())?;
+    Option::from(Address::from_raw_address_bytes(&bytes)).ok_or_else(invalid)
+}
+
+pub fn encode_fvk(fvk: &FullViewingKey) -> String {
+    bech32::encode(FVK_HRP, fvk.to_bytes().to_base32(), Variant::Bech32m).unwrap()
+}
+
+pub fn encode_ivk(ivk: &IncomingViewingKey) -> String {
+    bech32::encode(IVK_HRP, ivk.to_bytes().to_base32(), Variant::Bech32m).unwrap()
+}
+
+pub fn encode_spending_key(sk: &SpendingKey) -> String {
+    bech32::encode(
+        SPENDING_KEY_HRP,
+        sk.to_bytes().to_base32(),
+        Variant::Bech32m,
+    )
+    .unwrap()
+}
+
+pub fn decode_spending_key(s: &str) -> Result<SpendingKey> {
+    let (hrp, data) = decode(s)?;
+    if hrp != SPENDING_KEY_HRP {
+        return Err(invalid(format!("unknown spending key prefix '{}'", hrp)));
+    }
+    let bytes: [u8; 32] = data
+        .try_into()
+        .map_err(|_| invalid("spending key has the wrong length"))?;
+    Option::from(SpendingKey::from_bytes(bytes)).ok_or_else(|| invalid("invalid spending key"))
+}
+
+/// Decodes a Bech32m full or incoming viewing key, telling the two apart by prefix.
+pub fn decode_viewing_key(s: &str) -> Result<ViewingKey> {
+    let (hrp, data) = decode(s)?;
+    match hrp.as_str() {
+        FVK_HRP => {
+            let bytes: [u8; 96] = data
+                .try_into()
+                .map_err(|_| invalid("full viewing key has the wrong length"))?;
+            FullViewingKey::from_bytes(&bytes)
+                .map(ViewingKey::Full)
+                .ok_or_else(|| invalid("invalid full viewing key"))
+        }
+        IVK_HRP => {
+            let bytes: [u8; 64] = data
+                .try_into()
+                .map_err(|_| invalid("incoming viewing key has the wrong length"))?;
+            Option::from(IncomingViewingKey::from_bytes(&bytes))
+                .map(ViewingKey::Incoming)
+                .ok_or_else(|| invalid("invalid incoming viewing key"))
+        }
+        _ => Err(invalid(format!("unknown viewing key prefix '{}'", hrp))),
+    }
+}
+
+fn decode(s: &str) -> Result<(String, Vec<u8>)> {
+    let (hrp, data, variant) =
+        bech32::decode(s).map_err(|e| invalid(format!("invalid encoding: {}", e)))?;
+    if variant != Variant::Bech32m {
+        return Err(invalid("expected Bech32m encoding"));
+    }
+    let bytes =
+        Vec::<u8>::from_base32(&data).map_err(|e| invalid(format!("invalid encoding: {}", e)))?;
+    Ok((hrp, bytes))
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidInput(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orchard::keys::Scope;
+
+    fn keys() -> (SpendingKey, FullViewingKey) {
+        let sk = SpendingKey::from_bytes([5; 32]).unwrap();
+        let fvk = FullViewingKey::from(&sk);
+        (sk, fvk)
+    }
+
+    #[test]
+    fn addresses_round_trip() {
+        let (_, fvk) = keys();
+        let address = fvk.address_at(3u32, Scope::External);
+        let encoded = encode_address(&address);
+        assert!(encoded.starts_with(ADDRESS_HRP));
+        assert_eq!(decode_address(&encoded).unwrap(), address);
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        let (sk, fvk) = keys();
+        let decoded = decode_spending_key(&encode_spending_key(&sk)).unwrap();
+        assert_eq!(decoded.to_bytes(), sk.to_bytes());
+
+        match decode_viewing_key(&encode_fvk(&fvk)).unwrap() {
+            ViewingKey::Full(decoded) => assert_eq!(decoded.to_bytes(), fvk.to_bytes()),
+            ViewingKey::Incoming(_) => panic!("decoded a full viewing key as incoming"),
+        }
+        let ivk = fvk.to_ivk(Scope::External);
+        match decode_viewing_key(&encode_ivk(&ivk)).unwrap() {
+            ViewingKey::Incoming(decoded) => assert_eq!(decoded.to_bytes(), ivk.to_bytes()),
+            ViewingKey::Full(_) => panic!("decoded an incoming viewing key as full"),
+        }
+    }
+
+    #[test]
+    fn prefixes_are_checked() {
+        let (sk, fvk) = keys();
+        let address = encode_address(&fvk.address_at(0u32, Scope::External));
+        let fvk = encode_fvk(&fvk);
+        assert!(matches!(
+            decode_address(&fvk),
+            Err(Error::InvalidAddress(a)) if a == fvk
+        ));
+        assert!(matches!(
+            decode_spending_key(&address),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            decode_viewing_key(&encode_spending_key(&sk)),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            decode_address("tzo1qqqq"),
+            Err(Error::InvalidAddress(_))
+        ));
+    }
+}
@@ -0,0 +1,108 @@
+use sled::transaction::TransactionError;
+use std::{fmt, io};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Why a library call failed.
+#[derive(Debug)]
+pub enum Error {
+    /// Neither a valid transparent nor a valid shielded address.
+    InvalidAddress(String),
+    /// A valid address that the wallet file does not hold.
+    UnknownAddress(String),
+    /// A key, mnemonic, memo, outpoint or other encoded input that does not parse.
+    InvalidInput(String),
+    /// The spendable funds do not cover a payment.
+    InsufficientFunds {
+        available: u64,
+        required: u64,
+    },
+    /// The wallet's secrets are encrypted and it is not unlocked.
+    WalletLocked,
+    /// The wallet lacks the key an operation needs, e.g. it is watch-only.
+    MissingKey(String),
+    /// A transaction failed validation and was not mined.
+    InvalidTransaction(String),
+    /// No chain has been created yet.
+    NoBlockchain,
+    /// The chain database is inconsistent, e.g. a block it refers to is missing.
+    CorruptDatabase(String),
+    /// Building, proving or authorizing an Orchard bundle failed.
+    Bundle(String),
+    Database(sled::Error),
+    Io(io::Error),
+    Serialization(serde_json::Error),
+}
+
+impl Error {
+    /// Wraps an error of the orchard builder, which only implements `Debug`.
+    pub(crate) fn bundle(e: impl fmt::Debug) -> Self {
+        Error::Bundle(format!("{:?}", e))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidAddress(address) => write!(f, "'{}' is not a valid address", address),
+            Error::UnknownAddress(address) => write!(f, "unknown address '{}'", address),
+            Error::InvalidInput(reason) => write!(f, "{}", reason),
+            Error::InsufficientFunds {
+                available,
+                required,
+            } => write!(
+                f,
+                "insufficient funds: have {}, need {}",
+                available, required
+            ),
+            Error::WalletLocked => write!(f, "wallet is locked; unlock it with walletpassphrase"),
+            Error::MissingKey(reason) => write!(f, "{}", reason),
+            Error::InvalidTransaction(reason) => write!(f, "invalid transaction: {}", reason),
+            Error::NoBlockchain => {
+                write!(f, "no blockchain found; create one with createblockchain")
+            }
+            Error::CorruptDatabase(reason) => write!(f, "corrupt database: {}", reason),
+            Error::Bundle(reason) => write!(f, "failed to build the shielded bundle: {}", reason),
+            Error::Database(e) => write!(f, "database error: {}", e),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Serialization(e) => write!(f, "malformed data: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Database(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::Database(e)
+    }
+}
+
+impl From<TransactionError<sled::Error>> for Error {
+    fn from(e: TransactionError<sled::Error>) -> Self {
+        match e {
+            TransactionError::Abort(e) | TransactionError::Storage(e) => Error::Database(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
@@ -0,0 +1,384 @@
+use crate::{
+    block::Block,
+    blockchain, encoding,
+    error::Result,
+    memo,
+    wallet::{self, Note, Wallet},
+};
+use orchard::keys::{IncomingViewingKey, Scope};
+use std::collections::{HashMap, HashSet};
+
+/// Balances of a set of wallets by pool. Funds with fewer confirmations than asked for
+/// are counted as unconfirmed instead.
+#[derive(Default)]
+pub struct Balance {
+    pub transparent: u64,
+    pub transparent_unconfirmed: u64,
+    pub shielded: u64,
+    pub shielded_unconfirmed: u64,
+}
+
+impl Balance {
+    pub fn total(&self) -> u64 {
+        self.transparent + self.shielded
+    }
+
+    fn add_outputs(&mut self, blocks: &[Block], pub_key_hash: &Vec<u8>, minconf: u64) {
+        let tip = blocks.len() as u64;
+        for utxo in blockchain::find_utxos_in(blocks, pub_key_hash) {
+            if tip - utxo.height >= minconf {
+                self.transparent += utxo.value as u64;
+            } else {
+                self.transparent_unconfirmed += utxo.value as u64;
+            }
+        }
+    }
+
+    fn add_notes<'a>(
+        &mut self,
+        blocks: &[Block],
+        notes: impl Iterator<Item = &'a Note>,
+        minconf: u64,
+    ) {
+        let tip = blocks.len() as u64;
+        let heights = block_heights(blocks);
+        for note in notes {
+            if note.is_spent() {
+                continue;
+            }
+            // Notes from before block hashes were recorded are long confirmed.
+            let confirmations = heights.get(&note.block).map_or(tip, |h| tip - h);
+            if confirmations >= minconf {
+                self.shielded += note.value;
+            } else {
+                self.shielded_unconfirmed += note.value;
+            }
+        }
+    }
+}
+
+/// One transaction's effect on a set of wallets.
+pub struct Entry {
+    pub height: u64,
+    pub confirmations: u64,
+    pub txid: String,
+    /// "receive", "send", or "self" when the wallets' funds only moved between them.
+    pub direction: &'static str,
+    /// Net change of the wallets' funds across both pools.
+    pub amount: i64,
+    /// Fee paid, for transactions the wallets funded.
+    pub fee: Option<i64>,
+    pub memo: String,
+    /// Addresses outside the wallets that a transaction they funded paid.
+    pub recipients: Vec<String>,
+}
+
+/// Sums the unspent transparent outputs and notes of `wallets`, counting those in the
+/// last `minconf - 1` blocks as unconfirmed. A block at the tip has one confirmation.
+pub fn balance(blocks: &[Block], wallets: &[&Wallet], minconf: u64) -> Balance {
+    let mut balance = Balance::default();
+    for key in pub_key_hashes(wallets) {
+        balance.add_outputs(blocks, &key, minconf);
+    }
+    balance.add_notes(blocks, wallets.iter().flat_map(|w| &w.notes), minconf);
+    balance
+}
+
+/// Like [`balance`], for a single address of `wallet`: the outputs locked to a transparent
+/// address, or the notes received at a shielded one.
+pub fn address_balance(
+    blocks: &[Block],
+    wallet: &Wallet,
+    address: &str,
+    minconf: u64,
+) -> Result<Balance> {
+    let mut balance = Balance::default();
+    if wallet::validate_address(address) {
+        balance.add_outputs(blocks, &wallet::decode_address(address)?, minconf);
+    } else {
+        let recipient = hex::encode(wallet::parse_z_address(address)?.to_raw_address_bytes());
+        let notes = wallet.notes.iter().filter(|n| n.recipient == recipient);
+        balance.add_notes(blocks, notes, minconf);
+    }
+    Ok(balance)
+}
+
+/// Lists the transactions touching `wallets` in chain order, from either pool.
+pub fn list_transactions(blocks: &[Block], wallets: &[&Wallet]) -> Vec<Entry> {
+    let tip = blocks.len() as u64;
+    let keys = pub_key_hashes(wallets);
+    let notes: Vec<&Note> = wallets.iter().flat_map(|w| &w.notes).collect();
+    let by_nullifier: HashMap<[u8; 32], &Note> = notes
+        .iter()
+        .filter(|n| n.nullifier != [0u8; 32])
+        .map(|n| (n.nullifier, *n))
+        .collect();
+    let change: HashSet<String> = wallets
+        .iter()
+        .filter_map(|w| w.fvk())
+        .map(|fvk| hex::encode(fvk.address_at(0u32, Scope::Internal).to_raw_address_bytes()))
+        .collect();
+    let owned: Vec<IncomingViewingKey> = wallets.iter().flat_map(|w| w.ivks()).collect();
+
+    // Every output seen so far, to value the inputs spending them.
+    let mut outputs: HashMap<(String, i32), (i64, bool)> = HashMap::new();
+    let mut entries = vec![];
+    for (height, block) in blocks.iter().enumerate() {
+        let height = height as u64;
+        for tx in &block.transactions {
+            let txid = hex::encode(&tx.id);
+
+            let (mut inputs, mut spent) = (0, 0);
+            if !tx.is_coinbase() {
+                for vin in &tx.vin {
+                    let outpoint = (hex::encode(&vin.txid), vin.vout);
+                    if let Some((value, mine)) = outputs.get(&outpoint) {
+                        inputs += value;
+                        if *mine {
+                            spent += value;
+                        }
+                    }
+                }
+            }
+            let (mut outs, mut received) = (0, 0);
+            let mut recipients = vec![];
+            for (i, out) in tx.vout.iter().enumerate() {
+                let mine = keys.contains(&out.pub_key_hash);
+                outs += out.value;
+                if mine {
+                    received += out.value;
+                } else {
+                    recipients.push(wallet::address_of_pub_key_hash(&out.pub_key_hash));
+                }
+                outputs.insert((txid.clone(), i as i32), (out.value, mine));
+            }
+
+            if let Some(bundle) = tx.bundle.to_orchard() {
+                for action in bundle.actions() {
+                    if let Some(note) = by_nullifier.get(&action.nullifier().to_bytes()) {
+                        spent += note.value as i64;
+                    }
+                }
+            }
+            let mut memos = vec![];
+            for note in notes.iter().filter(|n| n.txid == txid) {
+                received += note.value as i64;
+                if !change.contains(&note.recipient) {
+                    memos.push(&note.memo);
+                }
+            }
+            for sent in wallets.iter().flat_map(|w| &w.sent) {
+                if sent.txid == txid {
+                    memos.push(&sent.memo);
+                    if let Ok(to) = wallet::parse_z_address(&sent.recipient) {
+                        // Payments between the wallets show up as received notes.
+                        if !owned.iter().any(|ivk| ivk.diversifier_index(&to).is_some()) {
+                            recipients.push(encoding::encode_address(&to));
+                        }
+                    }
+                }
+            }
+
+            if spent == 0 && received == 0 {
+                continue;
+            }
+            if spent == 0 {
+                recipients.clear();
+            }
+            let amount = received - spent;
+            let fee =
+                (spent > 0 && !tx.is_coinbase()).then(|| inputs - outs + tx.bundle.value_balance());
+            let memo = memos
+                .into_iter()
+                .map(|m| memo::display_memo(&hex::decode(m).unwrap_or_default()))
+                .find(|m| !m.is_empty())
+                .unwrap_or_default();
+            entries.push(Entry {
+                height,
+                confirmations: tip - height,
+                txid,
+                direction: match amount {
+                    a if a > 0 => "receive",
+                    a if a < 0 => "send",
+                    _ => "self",
+                },
+                amount,
+                fee,
+                memo,
+                recipients,
+            });
+        }
+    }
+    entries
+}
+
+fn block_heights(blocks: &[Block]) -> HashMap<String, u64> {
+    blocks
+        .iter()
+        .enumerate()
+        .map(|(height, b)| (hex::encode(&b.hash), height as u64))
+        .collect()
+}
+
+fn pub_key_hashes(wallets: &[&Wallet]) -> HashSet<Vec<u8>> {
+    wallets
+        .iter()
+        .filter(|w| w.has_transparent())
+        .map(|w| wallet::hash_pub_key(w.public_key.as_bytes()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+    use crate::transaction_input::TXInput;
+    use crate::transaction_output::TXOutput;
+    use crate::wallet::tests::{received_note, wallet};
+
+    fn tx(vin: Vec<TXInput>, vout: Vec<TXOutput>) -> Transaction {
+        let mut tx = Transaction {
+            vin,
+            vout,
+            ..Default::default()
+        };
+        tx.set_id();
+        tx
+    }
+
+    fn coinbase(value: i64, to: &str) -> Transaction {
+        let input = TXInput {
+            txid: vec![],
+            vout: -1,
+            signature: vec![],
+            pub_key: b"coinbase".to_vec(),
+        };
+        tx(vec![input], vec![TXOutput::new(value, to).unwrap()])
+    }
+
+    fn chain(blocks: Vec<Vec<Transaction>>) -> Vec<Block> {
+        blocks
+            .into_iter()
+            .enumerate()
+            .map(|(height, transactions)| Block {
+                prev_block_hash: vec![],
+                transactions,
+                timestamp: 0,
+                hash: vec![height as u8 + 1; 32],
+                nonce: 0,
+            })
+            .collect()
+    }
+
+    /// A chain where the wallet mines 10, pays 3 of it away keeping 7 as change, and
+    /// receives a note of 4 in the last block, plus the wallet with that note and a note
+    /// of 2 it has spent.
+    fn history() -> (Vec<Block>, Wallet, String) {
+        let mut me = wallet(1);
+        let other = wallet(2).get_address();
+        let mined = coinbase(10, &me.get_address());
+        let spend = tx(
+            vec![TXInput {
+                txid: mined.id.clone(),
+                vout: 0,
+                signature: vec![],
+                pub_key: me.public_key.clone().into_bytes(),
+            }],
+            vec![
+                TXOutput::new(3, &other).unwrap(),
+                TXOutput::new(7, &me.get_address()).unwrap(),
+            ],
+        );
+        let shielding = tx(vec![], vec![]);
+        let blocks = chain(vec![
+            vec![mined],
+            vec![spend],
+            vec![coinbase(50, &other), shielding.clone()],
+        ]);
+
+        let fvk = me.fvk().unwrap();
+        let mut received = received_note(&fvk, me.z_address().unwrap(), 4, &shielding.id);
+        received.block = hex::encode(&blocks[2].hash);
+        let mut spent = received_note(&fvk, me.z_address().unwrap(), 2, &[9; 32]);
+        spent.block = hex::encode(&blocks[0].hash);
+        spent.spent_in = Some(hex::encode(&blocks[1].hash));
+        me.notes = vec![received, spent];
+        (blocks, me, other)
+    }
+
+    #[test]
+    fn balance_counts_unspent_funds_by_confirmations() {
+        let (blocks, me, _) = history();
+        let b = balance(&blocks, &[&me], 1);
+        assert_eq!((b.transparent, b.transparent_unconfirmed), (7, 0));
+        assert_eq!((b.shielded, b.shielded_unconfirmed), (4, 0));
+        assert_eq!(b.total(), 11);
+
+        // The change has two confirmations, the note in the tip block only one.
+        let b = balance(&blocks, &[&me], 2);
+        assert_eq!((b.transparent, b.transparent_unconfirmed), (7, 0));
+        assert_eq!((b.shielded, b.shielded_unconfirmed), (0, 4));
+    }
+
+    #[test]
+    fn address_balance_counts_only_that_address() {
+        let (blocks, mut me, _) = history();
+        let b = address_balance(&blocks, &me, &me.get_address(), 1).unwrap();
+        assert_eq!((b.transparent, b.shielded), (7, 0));
+
+        let zaddr = me.get_z_address().unwrap();
+        let b = address_balance(&blocks, &me, &zaddr, 1).unwrap();
+        assert_eq!((b.transparent, b.shielded), (0, 4));
+
+        let diversified = encoding::encode_address(&me.new_z_address().unwrap());
+        let b = address_balance(&blocks, &me, &diversified, 1).unwrap();
+        assert_eq!(b.total(), 0);
+    }
+
+    #[test]
+    fn transactions_list_both_pools_in_chain_order() {
+        let (blocks, me, other) = history();
+        let entries = list_transactions(&blocks, &[&me]);
+        let summary: Vec<(u64, u64, &str, i64, Option<i64>)> = entries
+            .iter()
+            .map(|e| (e.height, e.confirmations, e.direction, e.amount, e.fee))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, 3, "receive", 10, None),
+                (1, 2, "send", -3, Some(0)),
+                (2, 1, "receive", 4, None),
+            ]
+        );
+        assert!(entries[0].recipients.is_empty());
+        assert_eq!(entries[1].recipients, vec![other]);
+        assert_eq!(entries[2].txid, hex::encode(&blocks[2].transactions[1].id));
+        assert!(entries.iter().all(|e| e.memo.is_empty()));
+    }
+
+    #[test]
+    fn recipients_leave_out_the_wallets_own_addresses() {
+        let (blocks, mut me, other) = history();
+        let spend = blocks[1].transactions[0].id.clone();
+        let stranger = wallet(3);
+        let paid = received_note(
+            &stranger.fvk().unwrap(),
+            stranger.z_address().unwrap(),
+            1,
+            &spend,
+        );
+        let own = received_note(&me.fvk().unwrap(), me.new_z_address().unwrap(), 1, &spend);
+        for note in [paid, own] {
+            let note = note.try_to_note().unwrap();
+            me.sent
+                .push(wallet::SentNote::new(&note, &[0xf6; 512], &spend, ""));
+        }
+
+        let entries = list_transactions(&blocks, &[&me]);
+        assert_eq!(
+            entries[1].recipients,
+            vec![other, stranger.get_z_address().unwrap()]
+        );
+    }
+}
@@ -1,27 +1,38 @@
 use crate::block::{deserialize_block, Block};
+use crate::error::{Error, Result};
 
 pub struct BlockchainIterator<'a> {
     pub current_hash: Vec<u8>,
     pub db: &'a sled::Db,
 }
 
-impl<'a> BlockchainIterator<'a> {
-    pub fn next(&mut self) -> Option<Block> {
-        let mut block = None;
-        let encoded_block;
-        let b = self.db.open_tree("blocksBucket").unwrap();
-        match b.get(&self.current_hash) {
-            Ok(res) => match res {
-                Some(hash) => encoded_block = hash,
-                None => return block,
-            },
-            Err(_) => return block,
-        }
+impl Iterator for BlockchainIterator<'_> {
+    type Item = Result<Block>;
 
-        block = Some(deserialize_block(&encoded_block).unwrap());
-        if let Some(ref b) = block {
-            self.current_hash = b.prev_block_hash.clone();
+    /// Yields the blocks from the tip back to genesis. A block that is missing or does not
+    /// parse yields an error and ends the iteration.
+    fn next(&mut self) -> Option<Result<Block>> {
+        if self.current_hash.is_empty() {
+            return None;
+        }
+        let block = self.read_block();
+        match &block {
+            Ok(b) => self.current_hash = b.prev_block_hash.clone(),
+            Err(_) => self.current_hash.clear(),
         }
-        block
+        Some(block)
+    }
+}
+
+impl BlockchainIterator<'_> {
+    fn read_block(&self) -> Result<Block> {
+        let b = self.db.open_tree("blocksBucket")?;
+        let encoded_block = b.get(&self.current_hash)?.ok_or_else(|| {
+            Error::CorruptDatabase(format!(
+                "block {} is missing",
+                hex::encode(&self.current_hash)
+            ))
+        })?;
+        Ok(deserialize_block(&encoded_block)?)
     }
 }
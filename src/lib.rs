@@ -0,0 +1,31 @@
+//! A toy Zcash-like chain with transparent outputs and an Orchard shielded pool. Fallible
+//! calls return [`Result`], whose [`Error`] has a variant per kind of failure.
+
+pub mod block;
+pub mod blockchain;
+pub mod bundle;
+pub mod coinselect;
+mod crypto;
+pub mod deposit;
+pub mod encoding;
+pub mod error;
+pub mod history;
+pub mod iterator;
+pub mod memo;
+pub mod merkle;
+pub mod pczt;
+pub mod pow;
+pub mod scanner;
+pub mod sendmany;
+pub mod transaction;
+pub mod transaction_input;
+pub mod transaction_output;
+mod verify;
+pub mod wallet;
+pub mod wallets;
+pub mod withdraw;
+pub mod zsend;
+
+pub use blockchain::Blockchain;
+pub use error::{Error, Result};
+pub use wallets::Wallets;
@@ -1,25 +1,12 @@
+use std::process;
 use structopt::StructOpt;
 
-mod block;
-mod blockchain;
-mod bundle;
 mod cli;
-mod deposit;
-mod iterator;
-mod merkle;
-mod pow;
-mod transaction;
-mod transaction_input;
-mod transaction_output;
-mod verify;
-mod wallet;
-mod wallets;
-mod withdraw;
-mod zsend;
 
 fn main() {
-    let mut c = cli::Cli {
-        cmd: cli::Command::from_args(),
-    };
-    c.run();
+    let mut c = cli::Cli::from_args();
+    if let Err(e) = c.run() {
+        eprintln!("ERROR: {}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,71 @@
+use crate::error::{Error, Result};
+
+pub const MEMO_SIZE: usize = 512;
+
+/// Parses a memo given as text, or as binary data when prefixed with `0x`.
+pub fn parse_memo(s: &str) -> Result<[u8; MEMO_SIZE]> {
+    let bytes = match s.strip_prefix("0x") {
+        Some(hex_memo) => hex::decode(hex_memo)
+            .map_err(|e| Error::InvalidInput(format!("invalid hex memo: {}", e)))?,
+        None => s.as_bytes().to_vec(),
+    };
+    if bytes.len() > MEMO_SIZE {
+        return Err(Error::InvalidInput(format!(
+            "memo is longer than {} bytes",
+            MEMO_SIZE
+        )));
+    }
+    let mut memo = [0u8; MEMO_SIZE];
+    memo[..bytes.len()].copy_from_slice(&bytes);
+    Ok(memo)
+}
+
+/// Renders a memo as text when it holds UTF-8, as `0x`-prefixed hex otherwise, and as an
+/// empty string for the "no memo" encoding (a leading 0xF6 byte).
+pub fn display_memo(memo: &[u8]) -> String {
+    if memo.is_empty() || memo[0] == 0xf6 {
+        return String::new();
+    }
+    let end = memo.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    match std::str::from_utf8(&memo[..end]) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("0x{}", hex::encode(&memo[..end])),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_memos_round_trip() {
+        let memo = parse_memo("thanks for lunch").unwrap();
+        assert_eq!(display_memo(&memo), "thanks for lunch");
+    }
+
+    #[test]
+    fn binary_memos_round_trip_as_hex() {
+        let memo = parse_memo("0xff00fe").unwrap();
+        assert_eq!(&memo[..3], &[0xff, 0x00, 0xfe]);
+        assert_eq!(display_memo(&memo), "0xff00fe");
+    }
+
+    #[test]
+    fn empty_memos_display_as_nothing() {
+        let mut no_memo = [0u8; MEMO_SIZE];
+        no_memo[0] = 0xf6;
+        assert_eq!(display_memo(&no_memo), "");
+        assert_eq!(display_memo(&[]), "");
+        assert_eq!(display_memo(&parse_memo("").unwrap()), "");
+    }
+
+    #[test]
+    fn oversized_or_malformed_memos_are_rejected() {
+        assert!(parse_memo(&"a".repeat(MEMO_SIZE)).is_ok());
+        assert!(matches!(
+            parse_memo(&"a".repeat(MEMO_SIZE + 1)),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(parse_memo("0xzz"), Err(Error::InvalidInput(_))));
+    }
+}
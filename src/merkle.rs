@@ -1,8 +1,251 @@
-use bridgetree::BridgeTree;
-use lazy_static::lazy_static;
-use orchard::tree::MerkleHashOrchard;
+use incrementalmerkletree::{frontier::CommitmentTree, witness::IncrementalWitness};
+use orchard::note::ExtractedNoteCommitment;
+use orchard::tree::{Anchor, MerkleHashOrchard, MerklePath};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-lazy_static! {
-    pub static ref MERKLE: BridgeTree::<MerkleHashOrchard, u32, 32> =
-        BridgeTree::<MerkleHashOrchard, u32, 32>::new(100);
+const DEPTH: u8 = 32;
+
+/// The rightmost path of the note commitment tree: enough to append commitments and
+/// compute the root, without keeping the commitments themselves.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "StoredTree", into = "StoredTree")]
+pub struct Frontier(CommitmentTree<MerkleHashOrchard, DEPTH>);
+
+impl Default for Frontier {
+    fn default() -> Self {
+        Frontier(CommitmentTree::empty())
+    }
+}
+
+impl Frontier {
+    /// Appends `cmx` and returns its position.
+    pub fn append(&mut self, cmx: &ExtractedNoteCommitment) -> u64 {
+        // A tree of depth 32 only fills up after 2^32 commitments.
+        _ = self.0.append(MerkleHashOrchard::from_cmx(cmx));
+        self.size() - 1
+    }
+
+    /// Number of commitments appended.
+    pub fn size(&self) -> u64 {
+        self.0.size() as u64
+    }
+
+    pub fn root(&self) -> Anchor {
+        self.0.root().into()
+    }
+}
+
+/// The chain's note commitment tree as tracked by the wallet: its frontier, plus an
+/// incremental witness for each of the wallet's unspent notes. Both are advanced with
+/// every commitment scanned, so witnessing a note never replays the chain.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct NoteTree {
+    frontier: Frontier,
+    witnesses: BTreeMap<u64, Witness>,
+    /// Number of blocks whose commitments have been appended.
+    pub synced_height: u64,
+    /// Hex hash of the last block appended, used to detect reorgs.
+    pub synced_hash: String,
+}
+
+impl NoteTree {
+    /// Appends `cmx`, advancing every witness, and returns its position.
+    pub fn append(&mut self, cmx: &ExtractedNoteCommitment) -> u64 {
+        let node = MerkleHashOrchard::from_cmx(cmx);
+        for witness in self.witnesses.values_mut() {
+            _ = witness.0.append(node);
+        }
+        self.frontier.append(cmx)
+    }
+
+    /// Starts witnessing the commitment appended last.
+    pub fn mark(&mut self) {
+        if let Some(position) = self.frontier.size().checked_sub(1) {
+            let witness = IncrementalWitness::from_tree(self.frontier.0.clone());
+            self.witnesses.insert(position, Witness(witness));
+        }
+    }
+
+    pub fn unmark(&mut self, position: u64) {
+        self.witnesses.remove(&position);
+    }
+
+    pub fn is_marked(&self, position: u64) -> bool {
+        self.witnesses.contains_key(&position)
+    }
+
+    /// Number of commitments appended.
+    pub fn size(&self) -> u64 {
+        self.frontier.size()
+    }
+
+    /// Forgets every commitment and witness, so the tree can be rebuilt from the chain.
+    pub fn reset(&mut self) {
+        *self = NoteTree::default();
+    }
+
+    pub fn anchor(&self) -> Anchor {
+        self.frontier.root()
+    }
+
+    /// Returns the current anchor with a Merkle path to it for each marked position.
+    pub fn witnesses(&self, positions: &[u64]) -> Option<(Anchor, Vec<MerklePath>)> {
+        let (anchor, auth_paths) = self.auth_paths(positions)?;
+        let paths = positions
+            .iter()
+            .zip(auth_paths)
+            .map(|(&position, auth_path)| {
+                Some(MerklePath::from_parts(position.try_into().ok()?, auth_path))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some((anchor, paths))
+    }
+
+    /// Like [`NoteTree::witnesses`], but returns the sibling hashes of each path, leaf
+    /// first, so they can be serialized.
+    pub fn auth_paths(&self, positions: &[u64]) -> Option<(Anchor, Vec<[MerkleHashOrchard; 32]>)> {
+        let paths = positions
+            .iter()
+            .map(|position| {
+                let path = self.witnesses.get(position)?.0.path()?;
+                path.path_elems().try_into().ok()
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some((self.anchor(), paths))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(try_from = "StoredWitness", into = "StoredWitness")]
+struct Witness(IncrementalWitness<MerkleHashOrchard, DEPTH>);
+
+impl From<Witness> for StoredWitness {
+    fn from(witness: Witness) -> Self {
+        StoredWitness {
+            tree: StoredTree::from(witness.0.tree()),
+            filled: witness.0.filled().iter().map(encode_node).collect(),
+            cursor: witness.0.cursor().as_ref().map(StoredTree::from),
+        }
+    }
+}
+
+impl TryFrom<StoredWitness> for Witness {
+    type Error = String;
+
+    fn try_from(stored: StoredWitness) -> Result<Self, String> {
+        let filled = stored
+            .filled
+            .iter()
+            .map(|node| decode_node(node))
+            .collect::<Result<_, _>>()?;
+        let cursor = stored.cursor.map(|c| c.to_tree()).transpose()?;
+        Ok(Witness(IncrementalWitness::from_parts(
+            stored.tree.to_tree()?,
+            filled,
+            cursor,
+        )))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredWitness {
+    tree: StoredTree,
+    filled: Vec<String>,
+    cursor: Option<StoredTree>,
+}
+
+/// A commitment tree with its nodes hex encoded.
+#[derive(Serialize, Deserialize)]
+struct StoredTree {
+    left: Option<String>,
+    right: Option<String>,
+    parents: Vec<Option<String>>,
+}
+
+impl From<&CommitmentTree<MerkleHashOrchard, DEPTH>> for StoredTree {
+    fn from(tree: &CommitmentTree<MerkleHashOrchard, DEPTH>) -> Self {
+        StoredTree {
+            left: tree.left().as_ref().map(encode_node),
+            right: tree.right().as_ref().map(encode_node),
+            parents: tree
+                .parents()
+                .iter()
+                .map(|p| p.as_ref().map(encode_node))
+                .collect(),
+        }
+    }
+}
+
+impl From<Frontier> for StoredTree {
+    fn from(frontier: Frontier) -> Self {
+        StoredTree::from(&frontier.0)
+    }
+}
+
+impl TryFrom<StoredTree> for Frontier {
+    type Error = String;
+
+    fn try_from(stored: StoredTree) -> Result<Self, String> {
+        stored.to_tree().map(Frontier)
+    }
+}
+
+impl StoredTree {
+    fn to_tree(&self) -> Result<CommitmentTree<MerkleHashOrchard, DEPTH>, String> {
+        let decode = |node: &Option<String>| node.as_deref().map(decode_node).transpose();
+        let parents = self.parents.iter().map(decode).collect::<Result<_, _>>()?;
+        CommitmentTree::from_parts(decode(&self.left)?, decode(&self.right)?, parents)
+            .map_err(|_| "note commitment tree is too deep".to_string())
+    }
+}
+
+fn encode_node(node: &MerkleHashOrchard) -> String {
+    hex::encode(node.to_bytes())
+}
+
+fn decode_node(node: &str) -> Result<MerkleHashOrchard, String> {
+    let bytes: [u8; 32] = hex::decode(node)
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| format!("malformed tree node '{}'", node))?;
+    Option::from(MerkleHashOrchard::from_bytes(&bytes))
+        .ok_or_else(|| format!("malformed tree node '{}'", node))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cmx(n: u8) -> ExtractedNoteCommitment {
+        ExtractedNoteCommitment::from_bytes(&[n; 32]).unwrap()
+    }
+
+    #[test]
+    fn witnesses_follow_the_tree_as_it_grows() {
+        let mut tree = NoteTree::default();
+        let mut frontier = Frontier::default();
+        for n in 1..=6 {
+            tree.append(&cmx(n));
+            frontier.append(&cmx(n));
+            if n == 2 {
+                tree.mark();
+            }
+        }
+        assert_eq!(tree.anchor(), frontier.root());
+        let (anchor, paths) = tree.witnesses(&[1]).unwrap();
+        assert_eq!(paths[0].root(cmx(2)), anchor);
+        assert!(tree.witnesses(&[0]).is_none());
+
+        // Stored witnesses keep advancing after they are read back.
+        let mut stored: NoteTree =
+            serde_json::from_str(&serde_json::to_string(&tree).unwrap()).unwrap();
+        stored.append(&cmx(7));
+        let (anchor, paths) = stored.witnesses(&[1]).unwrap();
+        assert_ne!(anchor, tree.anchor());
+        assert_eq!(paths[0].root(cmx(2)), anchor);
+
+        stored.unmark(1);
+        assert!(stored.witnesses(&[1]).is_none());
+    }
 }
@@ -0,0 +1,581 @@
+use crate::{
+    blockchain::Blockchain,
+    coinselect::Selection,
+    encoding::{self, ViewingKey},
+    error::{Error, Result},
+    memo::MEMO_SIZE,
+    merkle,
+    sendmany::{self, Destination, Recipient},
+    transaction::{self, Transaction},
+    transaction_output::TXOutput,
+    wallet::{self, Note},
+    wallets::{self, Wallets},
+    zsend,
+};
+use orchard::{
+    builder::{Builder, InProgress, MaybeSigned, Unauthorized, Unproven},
+    bundle::Flags,
+    circuit::ProvingKey,
+    keys::{FullViewingKey, Scope, SpendAuthorizingKey},
+    note_encryption::OrchardDomain,
+    primitives::redpallas::{self, SpendAuth},
+    tree::{Anchor, MerkleHashOrchard, MerklePath},
+    value::NoteValue,
+    Address, Bundle,
+};
+use rand::rngs::OsRng;
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use zcash_note_encryption::try_output_recovery_with_ovk;
+
+/// A partially created transaction, handed between the roles that complete it: a creator
+/// needing only viewing keys, a signer holding the spending keys, and a prover. The
+/// Orchard bundle travels as the plan it is built from plus the seed of the RNG building
+/// it, so every role rebuilds exactly the same bundle and signs or proves that.
+///
+/// Orchard only exposes a proof as part of a fully authorized bundle, so the prover runs
+/// after the spend authorizations are collected: create, sign, prove, then broadcast.
+#[derive(Serialize, Deserialize)]
+pub struct Pczt {
+    /// The transparent inputs and outputs, with the bundle attached once proven.
+    tx: Transaction,
+    plan: Plan,
+    /// Hex spend authorization signatures collected from signers.
+    #[serde(default)]
+    spend_auth_sigs: Vec<String>,
+}
+
+/// What a partial transaction pays, for a signer to review before signing.
+pub struct Summary {
+    /// Each recipient, transparent or shielded, with the amount paid, change included.
+    pub outputs: Vec<(String, u64)>,
+    /// Value of the inputs that no output receives.
+    pub fee: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Plan {
+    /// Hex seed of the RNG the bundle is built with.
+    seed: String,
+    flags: u8,
+    /// Hex anchor the spent notes are witnessed against.
+    anchor: String,
+    /// Bech32m full viewing key of the sender. It owns the spent notes, and outputs are
+    /// encrypted to its outgoing viewing key so the sender can recover them.
+    fvk: Option<String>,
+    spends: Vec<Spend>,
+    outputs: Vec<Output>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Spend {
+    note: Note,
+    /// Hex sibling hashes of the note's Merkle path, leaf first.
+    auth_path: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Output {
+    /// Bech32m recipient address.
+    recipient: String,
+    value: u64,
+    /// Hex memo, if any.
+    memo: Option<String>,
+}
+
+impl Pczt {
+    /// Plans a transaction paying every recipient from `from`, which may be a transparent
+    /// or a shielded address. Only public and viewing keys are used, so a watch-only
+    /// wallet can create it.
+    pub fn create(from: &str, recipients: &[Recipient], bc: &Blockchain) -> Result<Pczt> {
+        if recipients.is_empty() {
+            return Err(invalid("no recipients given"));
+        }
+
+        let mut outputs = vec![];
+        let mut transparent_total = 0;
+        let mut shielded = vec![];
+        for r in recipients {
+            match sendmany::destination(&r.address)? {
+                Destination::Transparent(addr) => {
+                    outputs.push(TXOutput::new(to_value(r.amount)?, &addr)?);
+                    transparent_total = sum([transparent_total, r.amount])?;
+                }
+                Destination::Shielded(addr) => shielded.push((addr, r)),
+            }
+        }
+        let shielded_total = sum(shielded.iter().map(|(_, r)| r.amount))?;
+        let amount = sum([transparent_total, shielded_total])?;
+
+        let wallets = Wallets::new()?;
+        let mut seed = [0u8; 32];
+        OsRng.fill_bytes(&mut seed);
+
+        // Funding from a transparent address spends its outputs into the pool; funding from
+        // a shielded address spends notes and releases the transparent part from the pool.
+        let (tx, mut plan) = if let Some(wallet) = wallets.get_wallet(from) {
+            if shielded.is_empty() {
+                return Err(invalid("transparent-only payments should use send"));
+            }
+            let tx = transaction::new_funded_transaction(
+                from,
+                outputs,
+                to_value(amount)?,
+                &Selection::default(),
+                bc,
+            )?;
+            let anchor = merkle::Frontier::default().root();
+            let flags = Flags::from_parts(false, true);
+            (tx, Plan::new(seed, flags, anchor, wallet.fvk().as_ref()))
+        } else {
+            let wallet = wallets
+                .get_z_wallet(from)
+                .ok_or_else(|| Error::UnknownAddress(from.to_string()))?;
+            let fvk = wallet.fvk().ok_or_else(|| {
+                Error::MissingKey("spending needs the full viewing key of the sender".to_string())
+            })?;
+            let (selected, total) = zsend::select_notes(&wallet.notes, amount)?;
+            let positions: Vec<u64> = selected.iter().map(|n| n.position).collect();
+            let (anchor, auth_paths) = wallets.tree().auth_paths(&positions).ok_or_else(|| {
+                Error::Bundle("missing witness for a note; rescan the wallet".to_string())
+            })?;
+
+            let mut plan = Plan::new(seed, Flags::from_parts(true, true), anchor, Some(&fvk));
+            for (note, auth_path) in selected.into_iter().zip(auth_paths) {
+                plan.spends.push(Spend {
+                    note: note.clone(),
+                    auth_path: auth_path
+                        .iter()
+                        .map(|h| hex::encode(h.to_bytes()))
+                        .collect(),
+                });
+            }
+            if total > amount {
+                let change = fvk.address_at(0u32, Scope::Internal);
+                plan.add_output(&change, total - amount, None);
+            }
+            let mut tx = Transaction {
+                vout: outputs,
+                ..Default::default()
+            };
+            tx.set_id();
+            (tx, plan)
+        };
+
+        for (addr, r) in shielded {
+            plan.add_output(&addr, r.amount, r.memo);
+        }
+        Ok(Pczt {
+            tx,
+            plan,
+            spend_auth_sigs: vec![],
+        })
+    }
+
+    /// Lists what the transaction pays and its fee, after checking that the rebuilt bundle
+    /// pays exactly the planned outputs and that the outputs do not exceed the inputs.
+    pub fn summary(&self, bc: &Blockchain) -> Result<Summary> {
+        let bundle = self.plan.build()?;
+        self.plan.check(&bundle)?;
+
+        let mut outputs = vec![];
+        for vout in &self.tx.vout {
+            let value = u64::try_from(vout.value).map_err(|_| invalid("negative output value"))?;
+            outputs.push((wallet::address_of_pub_key_hash(&vout.pub_key_hash), value));
+        }
+        for output in &self.plan.outputs {
+            outputs.push((output.recipient.clone(), output.value));
+        }
+
+        let mut inputs = vec![];
+        for vin in &self.tx.vin {
+            let prev_tx = bc
+                .find_transaction(&vin.txid)?
+                .ok_or_else(|| invalid("an input spends an unknown transaction"))?;
+            let out = usize::try_from(vin.vout)
+                .ok()
+                .and_then(|vout| prev_tx.vout.get(vout))
+                .ok_or_else(|| invalid("an input spends an unknown output"))?;
+            inputs.push(u64::try_from(out.value).map_err(|_| invalid("negative input value"))?);
+        }
+        inputs.extend(self.plan.spends.iter().map(|spend| spend.note.value));
+
+        let fee = sum(inputs)?
+            .checked_sub(sum(outputs.iter().map(|(_, value)| *value))?)
+            .ok_or_else(|| invalid("outputs exceed inputs"))?;
+        Ok(Summary { outputs, fee })
+    }
+
+    /// Adds the signatures the keys in `wallets` can make: spend authorizations for the
+    /// sender's notes and the transparent inputs' signature, once [`Pczt::summary`] finds
+    /// the transaction consistent. Returns how many were added.
+    pub fn sign(&mut self, wallets: &Wallets, bc: &Blockchain) -> Result<usize> {
+        self.summary(bc)?;
+        let bundle = self.plan.build()?;
+        let sighash = self.tx.shielded_sighash(&bundle);
+        let mut added = 0;
+
+        let owner = self.plan.fvk.as_ref().and_then(|fvk| {
+            wallets
+                .all()
+                .into_iter()
+                .find(|w| w.fvk().map(|k| encoding::encode_fvk(&k)).as_ref() == Some(fvk))
+        });
+        if let (Some(wallet), false) = (owner, self.plan.spends.is_empty()) {
+            let ask = SpendAuthorizingKey::from(&wallet.sk()?);
+            // Dummy spends are signed by `prepare`, and earlier signatures are applied, so
+            // whatever is still unsigned is for this key.
+            let bundle = bundle
+                .prepare(OsRng, sighash)
+                .append_signatures(&self.signatures()?)
+                .map_err(Error::bundle)?;
+            let unsigned: Vec<bool> = bundle
+                .actions()
+                .iter()
+                .map(|a| matches!(a.authorization(), MaybeSigned::SigningMetadata(_)))
+                .collect();
+            let bundle = bundle.sign(OsRng, &ask);
+            for (action, unsigned) in bundle.actions().iter().zip(unsigned) {
+                if let (MaybeSigned::Signature(sig), true) = (action.authorization(), unsigned) {
+                    let sig: [u8; 64] = sig.into();
+                    self.spend_auth_sigs.push(hex::encode(sig));
+                    added += 1;
+                }
+            }
+        }
+
+        let unsigned = self.tx.vin.iter().any(|vin| vin.signature.is_empty());
+        let funder = self.tx.vin.first().and_then(|vin| {
+            wallets
+                .all()
+                .into_iter()
+                .find(|w| w.has_transparent() && w.public_key.as_bytes() == vin.pub_key)
+        });
+        if let (Some(wallet), true) = (funder, unsigned) {
+            let private_key = wallets::parse_private_key(&wallet.secret_key()?)?;
+            self.tx
+                .sign_inputs(&private_key, sighash, &bc.prev_txs(&self.tx)?)?;
+            added += self.tx.vin.len();
+        }
+        Ok(added)
+    }
+
+    /// Creates the bundle's proof, authorizes it with the collected signatures and attaches
+    /// it to the transaction. Needs no keys.
+    pub fn prove(&mut self) -> Result<()> {
+        let bundle = self.plan.build()?;
+        let sighash = self.tx.shielded_sighash(&bundle);
+        let pk = ProvingKey::build();
+        let authorized = bundle
+            .create_proof(&pk, OsRng)
+            .map_err(Error::bundle)?
+            .prepare(OsRng, sighash)
+            .append_signatures(&self.signatures()?)
+            .map_err(Error::bundle)?
+            .finalize()
+            .map_err(|_| {
+                Error::MissingKey("spend authorizations are missing; sign first".to_string())
+            })?;
+        self.tx.bundle = (&authorized).into();
+        self.tx.set_id();
+        Ok(())
+    }
+
+    /// Returns the completed transaction, once it is proven and every input is signed.
+    pub fn finalize(self) -> Result<Transaction> {
+        if self.tx.bundle.is_empty() {
+            return Err(Error::InvalidTransaction(
+                "the bundle is not proven yet".to_string(),
+            ));
+        }
+        if self.tx.vin.iter().any(|vin| vin.signature.is_empty()) {
+            return Err(Error::MissingKey(
+                "the transparent inputs are not signed yet".to_string(),
+            ));
+        }
+        Ok(self.tx)
+    }
+
+    pub fn read(path: &str) -> Result<Pczt> {
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn signatures(&self) -> Result<Vec<redpallas::Signature<SpendAuth>>> {
+        self.spend_auth_sigs
+            .iter()
+            .map(|sig| decode::<64>(sig).map(redpallas::Signature::from))
+            .collect()
+    }
+}
+
+impl Plan {
+    fn new(seed: [u8; 32], flags: Flags, anchor: Anchor, fvk: Option<&FullViewingKey>) -> Plan {
+        Plan {
+            seed: hex::encode(seed),
+            flags: flags.to_byte(),
+            anchor: hex::encode(anchor.to_bytes()),
+            fvk: fvk.map(encoding::encode_fvk),
+            spends: vec![],
+            outputs: vec![],
+        }
+    }
+
+    fn add_output(&mut self, recipient: &Address, value: u64, memo: Option<[u8; MEMO_SIZE]>) {
+        self.outputs.push(Output {
+            recipient: encoding::encode_address(recipient),
+            value,
+            memo: memo.map(hex::encode),
+        });
+    }
+
+    /// Checks that `bundle`, built from the plan, moves the planned value and, when the
+    /// sender's outgoing viewing key can recover them, pays exactly the planned outputs.
+    fn check(&self, bundle: &Bundle<InProgress<Unproven, Unauthorized>, i64>) -> Result<()> {
+        let spent = sum(self.spends.iter().map(|spend| spend.note.value))?;
+        let paid = sum(self.outputs.iter().map(|output| output.value))?;
+        let balance = i64::try_from(i128::from(spent) - i128::from(paid))
+            .map_err(|_| invalid("values out of range"))?;
+        if *bundle.value_balance() != balance {
+            return Err(invalid("the bundle does not move the planned value"));
+        }
+
+        let fvk = match &self.fvk {
+            Some(fvk) => match encoding::decode_viewing_key(fvk)? {
+                ViewingKey::Full(fvk) => fvk,
+                ViewingKey::Incoming(_) => return Err(invalid("expected a full viewing key")),
+            },
+            None => return Ok(()),
+        };
+        let ovk = fvk.to_ovk(Scope::External);
+        // Padding actions carry dummy notes of zero value that the key cannot recover.
+        let mut recovered: Vec<(String, u64)> = bundle
+            .actions()
+            .iter()
+            .filter_map(|action| {
+                try_output_recovery_with_ovk(
+                    &OrchardDomain::for_action(action),
+                    &ovk,
+                    action,
+                    action.cv_net(),
+                    &action.encrypted_note().out_ciphertext,
+                )
+            })
+            .map(|(note, recipient, _)| {
+                (encoding::encode_address(&recipient), note.value().inner())
+            })
+            .collect();
+        for output in &self.outputs {
+            let recipient = encoding::encode_address(&wallet::parse_z_address(&output.recipient)?);
+            match recovered
+                .iter()
+                .position(|(r, value)| *r == recipient && *value == output.value)
+            {
+                Some(i) => _ = recovered.swap_remove(i),
+                None => {
+                    return Err(invalid(format!(
+                        "the bundle does not pay {} to {}",
+                        output.value, output.recipient
+                    )))
+                }
+            }
+        }
+        if recovered.iter().any(|(_, value)| *value != 0) {
+            return Err(invalid("the bundle pays an output that is not planned"));
+        }
+        Ok(())
+    }
+
+    /// Replays the plan into a builder seeded from the plan, yielding the same unproven,
+    /// unsigned bundle every time.
+    fn build(&self) -> Result<Bundle<InProgress<Unproven, Unauthorized>, i64>> {
+        let flags = Flags::from_byte(self.flags).ok_or_else(|| invalid("invalid bundle flags"))?;
+        let anchor = Option::from(Anchor::from_bytes(decode(&self.anchor)?))
+            .ok_or_else(|| invalid("invalid anchor"))?;
+        let fvk = match &self.fvk {
+            Some(fvk) => match encoding::decode_viewing_key(fvk)? {
+                ViewingKey::Full(fvk) => Some(fvk),
+                ViewingKey::Incoming(_) => return Err(invalid("expected a full viewing key")),
+            },
+            None => None,
+        };
+
+        let mut builder = Builder::new(flags, anchor);
+        for spend in &self.spends {
+            let fvk = fvk
+                .clone()
+                .ok_or_else(|| invalid("spends need the sender's full viewing key"))?;
+            builder
+                .add_spend(fvk, spend.note.try_to_note()?, spend.merkle_path()?)
+                .map_err(Error::bundle)?;
+        }
+        let ovk = fvk.map(|fvk| fvk.to_ovk(Scope::External));
+        for output in &self.outputs {
+            let recipient = wallet::parse_z_address(&output.recipient)?;
+            let memo = match &output.memo {
+                Some(memo) => Some(decode::<MEMO_SIZE>(memo)?),
+                None => None,
+            };
+            builder
+                .add_recipient(
+                    ovk.clone(),
+                    recipient,
+                    NoteValue::from_raw(output.value),
+                    memo,
+                )
+                .map_err(Error::bundle)?;
+        }
+        let rng = ChaCha20Rng::from_seed(decode(&self.seed)?);
+        builder.build(rng).map_err(Error::bundle)
+    }
+}
+
+impl Spend {
+    fn merkle_path(&self) -> Result<MerklePath> {
+        let auth_path = self
+            .auth_path
+            .iter()
+            .map(|hash| {
+                Option::from(MerkleHashOrchard::from_bytes(&decode(hash)?))
+                    .ok_or_else(|| invalid("invalid Merkle path"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let auth_path: [MerkleHashOrchard; 32] = auth_path
+            .try_into()
+            .map_err(|_| invalid("invalid Merkle path length"))?;
+        let position = self
+            .note
+            .position
+            .try_into()
+            .map_err(|_| invalid("invalid note position"))?;
+        Ok(MerklePath::from_parts(position, auth_path))
+    }
+}
+
+fn decode<const N: usize>(field: &str) -> Result<[u8; N]> {
+    hex::decode(field)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| invalid(format!("invalid hex field '{}'", field)))
+}
+
+/// Adds up amounts, failing rather than wrapping around.
+fn sum(values: impl IntoIterator<Item = u64>) -> Result<u64> {
+    values
+        .into_iter()
+        .try_fold(0u64, |total, value| total.checked_add(value))
+        .ok_or_else(|| invalid("values out of range"))
+}
+
+/// Converts an amount to the signed value of a transparent output.
+fn to_value(amount: u64) -> Result<i64> {
+    i64::try_from(amount).map_err(|_| invalid(format!("amount {} is out of range", amount)))
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidInput(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::NoteTree;
+    use crate::wallet::tests::{received_note, wallet};
+    use orchard::note::ExtractedNoteCommitment;
+
+    /// A plan spending a note of 10 into a payment of 6 with a memo and change of 4.
+    fn planned() -> Pczt {
+        let sender = wallet(4);
+        let fvk = sender.fvk().unwrap();
+        let note = received_note(&fvk, sender.z_address().unwrap(), 10, &[1; 32]);
+        let mut tree = NoteTree::default();
+        tree.append(&Option::from(ExtractedNoteCommitment::from_bytes(&note.cmx)).unwrap());
+        tree.mark();
+        let (anchor, auth_paths) = tree.auth_paths(&[0]).unwrap();
+
+        let mut plan = Plan::new([3; 32], Flags::from_parts(true, true), anchor, Some(&fvk));
+        plan.spends.push(Spend {
+            note,
+            auth_path: auth_paths[0]
+                .iter()
+                .map(|h| hex::encode(h.to_bytes()))
+                .collect(),
+        });
+        let memo = crate::memo::parse_memo("rent").unwrap();
+        plan.add_output(&wallet(5).z_address().unwrap(), 6, Some(memo));
+        plan.add_output(&fvk.address_at(0u32, Scope::Internal), 4, None);
+        Pczt {
+            tx: Transaction::default(),
+            plan,
+            spend_auth_sigs: vec![],
+        }
+    }
+
+    fn commitments(pczt: &Pczt) -> Vec<[u8; 32]> {
+        let bundle = pczt.plan.build().unwrap();
+        bundle
+            .actions()
+            .iter()
+            .map(|a| a.cmx().to_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let pczt = planned();
+        let json = serde_json::to_string(&pczt).unwrap();
+        let read: Pczt = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&read).unwrap(), json);
+
+        // Every role rebuilds exactly the bundle the creator planned.
+        assert_eq!(commitments(&read), commitments(&pczt));
+        pczt.plan.check(&read.plan.build().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tampered_plans_fail_the_check() {
+        let pczt = planned();
+        let bundle = pczt.plan.build().unwrap();
+
+        let mut tampered = planned();
+        tampered.plan.outputs[0].value += 1;
+        assert!(matches!(
+            tampered.plan.check(&bundle),
+            Err(Error::InvalidInput(_))
+        ));
+
+        // Redirecting the payment keeps the value balance but not the recovered outputs.
+        let mut redirected = planned();
+        redirected.plan.outputs[0].recipient =
+            encoding::encode_address(&wallet(6).z_address().unwrap());
+        let bundle = redirected.plan.build().unwrap();
+        assert!(matches!(
+            pczt.plan.check(&bundle),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn malformed_fields_are_rejected() {
+        let mut pczt = planned();
+        pczt.plan.anchor = "00".to_string();
+        assert!(matches!(pczt.plan.build(), Err(Error::InvalidInput(_))));
+
+        let mut pczt = planned();
+        pczt.plan.outputs[0].recipient = "nowhere".to_string();
+        assert!(matches!(pczt.plan.build(), Err(Error::InvalidAddress(_))));
+    }
+
+    #[test]
+    fn totals_that_overflow_are_errors() {
+        assert_eq!(sum([u64::MAX - 1, 1]).unwrap(), u64::MAX);
+        assert!(matches!(sum([u64::MAX, 1]), Err(Error::InvalidInput(_))));
+        assert!(matches!(to_value(u64::MAX), Err(Error::InvalidInput(_))));
+    }
+}
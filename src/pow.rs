@@ -1,10 +1,9 @@
 use crate::block::Block;
-use hex::encode;
 use num::{bigint::BigUint, ToPrimitive};
 use sha2::{Digest, Sha256};
 
 const TARGET_BITS: u32 = 8;
-const MAX_NONCE: u64 = std::i64::MAX as u64;
+const MAX_NONCE: u64 = i64::MAX as u64;
 
 pub struct ProofOfWork<'a> {
     block: &'a Block,
@@ -48,7 +47,6 @@ impl<'a> ProofOfWork<'a> {
         let mut hash = [0u8; 32];
         let mut nonce = 0;
 
-        println!("Mining the block");
         while nonce < MAX_NONCE {
             let data = self.prepare_data(nonce);
 
@@ -58,13 +56,11 @@ impl<'a> ProofOfWork<'a> {
             hash.reverse();
 
             if hash_int < self.target {
-                print!("\r{}", encode(hash));
                 break;
             } else {
                 nonce += 1;
             }
         }
-        println!("\n");
 
         (nonce, hash)
     }
@@ -0,0 +1,373 @@
+use crate::{
+    block::Block,
+    blockchain::Blockchain,
+    error::Result,
+    merkle::NoteTree,
+    wallet::{self, Wallet},
+    wallets::{self, Wallets},
+};
+use orchard::{
+    keys::{FullViewingKey, IncomingViewingKey, PreparedIncomingViewingKey, Scope},
+    note_encryption::OrchardDomain,
+    primitives::redpallas::{Signature, SpendAuth},
+    Action,
+};
+use std::collections::HashSet;
+use zcash_note_encryption::{try_note_decryption, try_output_recovery_with_ovk};
+
+/// Scans the blocks each wallet has not seen yet and saves the notes found. The wallets
+/// are then up to date with the tip, which clears any pending chain update.
+pub fn sync(bc: &Blockchain) -> Result<()> {
+    let mut wallets = Wallets::new()?;
+    scan(&bc.blocks()?, &mut wallets)?;
+    wallets.save_to_file()?;
+    wallets::end_chain_update()
+}
+
+/// Trial-decrypts every action in blocks past each wallet's sync height with the wallet's
+/// incoming viewing keys, recording received notes with their position in the note
+/// commitment tree, and marks notes spent when their nullifiers appear on chain. Outputs
+/// the wallet sent are recovered with its outgoing viewing key, except those paying any of
+/// the wallets, whose notes are found as received instead. New
+/// commitments are appended to the wallet's tree, which starts witnessing unspent wallet
+/// notes as they are appended. The tree is rebuilt from genesis when a wallet is behind it
+/// or an unspent note lacks a witness.
+pub fn scan(blocks: &[Block], wallets: &mut Wallets) -> Result<()> {
+    let tip = blocks.len() as u64;
+    let tip_hash = blocks
+        .last()
+        .map(|b| hex::encode(&b.hash))
+        .unwrap_or_default();
+
+    let (wallets, tree) = wallets.wallets_and_tree_mut();
+    let mut wallets: Vec<&mut Wallet> = wallets.collect();
+    for wallet in wallets.iter_mut() {
+        rewind_if_reorged(wallet, blocks);
+    }
+    rewind_tree_if_reorged(tree, blocks);
+
+    let mut scanning: Vec<(
+        &mut Wallet,
+        Option<FullViewingKey>,
+        Vec<PreparedIncomingViewingKey>,
+    )> = wallets
+        .into_iter()
+        .map(|w| {
+            let fvk = w.fvk();
+            let ivks = w
+                .ivks()
+                .iter()
+                .map(PreparedIncomingViewingKey::new)
+                .collect();
+            (w, fvk, ivks)
+        })
+        .collect();
+    let owned: Vec<IncomingViewingKey> = scanning.iter().flat_map(|(w, _, _)| w.ivks()).collect();
+    // Notes found in blocks the tree has passed, or whose witness was dropped, can only be
+    // witnessed by appending every commitment again.
+    let unspent: HashSet<u64> = scanning
+        .iter()
+        .flat_map(|(w, _, _)| w.notes.iter())
+        .filter(|n| !n.is_spent())
+        .map(|n| n.position)
+        .collect();
+    let unwitnessed = unspent
+        .iter()
+        .any(|&position| position < tree.size() && !tree.is_marked(position));
+    if unwitnessed
+        || scanning
+            .iter()
+            .any(|(w, _, _)| w.synced_height < tree.synced_height)
+    {
+        tree.reset();
+    }
+
+    let behind = scanning.iter().any(|(w, _, _)| w.synced_height < tip);
+    if !behind && tree.synced_height >= tip {
+        return Ok(());
+    }
+
+    // Positions count every commitment from genesis, including blocks already scanned.
+    let mut position = 0u64;
+    for (height, block) in blocks.iter().enumerate() {
+        let height = height as u64;
+        let block_hash = hex::encode(&block.hash);
+        for tx in &block.transactions {
+            let bundle = match tx.bundle.to_orchard() {
+                Some(bundle) => bundle,
+                None => continue,
+            };
+            for action in bundle.actions() {
+                let appended = height >= tree.synced_height;
+                if appended {
+                    tree.append(action.cmx());
+                }
+                let mut received = false;
+
+                let nf = action.nullifier().to_bytes();
+                let domain = OrchardDomain::for_action(action);
+                for (wallet, fvk, ivks) in scanning.iter_mut() {
+                    if height < wallet.synced_height {
+                        continue;
+                    }
+                    for note in wallet.notes.iter_mut().filter(|n| n.nullifier == nf) {
+                        note.spent_in = Some(block_hash.clone());
+                    }
+                    for ivk in ivks.iter() {
+                        if let Some((note, _, memo)) = try_note_decryption(&domain, ivk, action) {
+                            received = true;
+                            let mut n =
+                                wallet::Note::new(&note, fvk.as_ref(), &memo, &tx.id, position);
+                            n.block = block_hash.clone();
+                            // A note already known, e.g. one migrated from an older record,
+                            // only has its chain location refreshed.
+                            match wallet.notes.iter_mut().find(|k| k.cmx == n.cmx) {
+                                Some(known) => {
+                                    known.position = n.position;
+                                    known.txid = n.txid;
+                                    known.block = n.block;
+                                }
+                                None => wallet.notes.push(n),
+                            }
+                        }
+                    }
+                    if let Some(fvk) = fvk {
+                        recover_sent(wallet, fvk, &owned, &domain, action, &tx.id, &block_hash);
+                    }
+                }
+                if appended && (received || unspent.contains(&position)) {
+                    tree.mark();
+                }
+                position += 1;
+            }
+        }
+    }
+
+    // Only unspent notes need witnesses.
+    for (wallet, _, _) in scanning {
+        for note in wallet.notes.iter().filter(|n| n.is_spent()) {
+            tree.unmark(note.position);
+        }
+        wallet.synced_height = tip;
+        wallet.synced_hash = tip_hash.clone();
+    }
+    tree.synced_height = tip;
+    tree.synced_hash = tip_hash;
+    Ok(())
+}
+
+fn recover_sent(
+    wallet: &mut Wallet,
+    fvk: &FullViewingKey,
+    owned: &[IncomingViewingKey],
+    domain: &OrchardDomain,
+    action: &Action<Signature<SpendAuth>>,
+    txid: &[u8],
+    block_hash: &str,
+) {
+    let ovk = fvk.to_ovk(Scope::External);
+    let recovered = try_output_recovery_with_ovk(
+        domain,
+        &ovk,
+        action,
+        action.cv_net(),
+        &action.encrypted_note().out_ciphertext,
+    );
+    let (note, recipient, memo) = match recovered {
+        Some(recovered) => recovered,
+        None => return,
+    };
+    if owned
+        .iter()
+        .any(|ivk| ivk.diversifier_index(&recipient).is_some())
+    {
+        return;
+    }
+    let sent = wallet::SentNote::new(&note, &memo, txid, block_hash);
+    if !wallet.sent.iter().any(|s| s.cmx == sent.cmx) {
+        wallet.sent.push(sent);
+    }
+}
+
+/// If the last block the wallet scanned is no longer on the chain, forgets the notes and
+/// spends learned from disconnected blocks and schedules a rescan from genesis.
+fn rewind_if_reorged(wallet: &mut Wallet, blocks: &[Block]) {
+    if is_on_chain(wallet.synced_height, &wallet.synced_hash, blocks) {
+        return;
+    }
+
+    let chain: HashSet<String> = blocks.iter().map(|b| hex::encode(&b.hash)).collect();
+    wallet.notes.retain(|n| chain.contains(&n.block));
+    wallet.sent.retain(|s| chain.contains(&s.block));
+    for note in wallet.notes.iter_mut() {
+        if note.spent_in.as_ref().is_some_and(|b| !chain.contains(b)) {
+            note.spent_in = None;
+        }
+    }
+    wallet.synced_height = 0;
+}
+
+fn rewind_tree_if_reorged(tree: &mut NoteTree, blocks: &[Block]) {
+    if !is_on_chain(tree.synced_height, &tree.synced_hash, blocks) {
+        tree.reset();
+    }
+}
+
+/// A sync point without a hash is a birthday set on creation or import, which is trusted
+/// until the first scan records the hash.
+fn is_on_chain(synced_height: u64, synced_hash: &str, blocks: &[Block]) -> bool {
+    if synced_height == 0 || synced_hash.is_empty() {
+        return true;
+    }
+    blocks
+        .get(synced_height as usize - 1)
+        .is_some_and(|b| hex::encode(&b.hash) == synced_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::Transaction;
+    use orchard::{
+        builder::Builder,
+        bundle::{Authorized, Flags},
+        circuit::Proof,
+        keys::OutgoingViewingKey,
+        note::ExtractedNoteCommitment,
+        tree::{MerkleHashOrchard, MerklePath},
+        value::NoteValue,
+        Address,
+    };
+    use rand::rngs::OsRng;
+
+    /// A transaction whose bundle spends `spent`, if given, and pays `value` to `to`,
+    /// recoverable with `ovk`. Scanning checks neither proofs nor signatures, so the
+    /// bundle has none.
+    fn payment(
+        spent: Option<(&FullViewingKey, orchard::Note)>,
+        ovk: Option<OutgoingViewingKey>,
+        to: Address,
+        value: u64,
+    ) -> Transaction {
+        let mut builder = match spent {
+            Some((fvk, note)) => {
+                let cmx = ExtractedNoteCommitment::from(note.commitment());
+                let path = MerklePath::from_parts(0, [MerkleHashOrchard::from_cmx(&cmx); 32]);
+                let mut builder = Builder::new(Flags::from_parts(true, true), path.root(cmx));
+                builder.add_spend(fvk.clone(), note, path).unwrap();
+                builder
+            }
+            None => Builder::new(Flags::from_parts(false, true), NoteTree::default().anchor()),
+        };
+        builder
+            .add_recipient(ovk, to, NoteValue::from_raw(value), None)
+            .unwrap();
+        let bundle: orchard::Bundle<Authorized, i64> =
+            builder.build(OsRng).unwrap().map_authorization(
+                &mut (),
+                |_, _, _| [0; 64].into(),
+                |_, _| Authorized::from_parts(Proof::new(vec![]), [0; 64].into()),
+            );
+        let mut tx = Transaction {
+            bundle: (&bundle).into(),
+            ..Default::default()
+        };
+        tx.set_id();
+        tx
+    }
+
+    fn block(transactions: Vec<Transaction>, hash: u8) -> Block {
+        Block {
+            prev_block_hash: vec![],
+            transactions,
+            timestamp: 0,
+            hash: vec![hash; 32],
+            nonce: 0,
+        }
+    }
+
+    fn stranger() -> Address {
+        wallet::tests::wallet(9).z_address().unwrap()
+    }
+
+    #[test]
+    fn scanning_finds_notes_paid_to_the_wallets() {
+        let mut wallets = Wallets::default();
+        let address = wallets.create_wallet(0).unwrap();
+        let to = wallets.get_wallet(&address).unwrap().z_address().unwrap();
+        let received = payment(None, None, to, 5);
+        let blocks = vec![
+            block(vec![payment(None, None, stranger(), 3)], 1),
+            block(vec![received.clone()], 2),
+        ];
+        scan(&blocks, &mut wallets).unwrap();
+        // Blocks already scanned are not scanned again.
+        scan(&blocks, &mut wallets).unwrap();
+
+        let wallet = wallets.get_wallet(&address).unwrap();
+        assert_eq!(wallet.notes.len(), 1);
+        let note = &wallet.notes[0];
+        assert_eq!(note.value, 5);
+        assert_eq!(note.txid, hex::encode(&received.id));
+        assert_eq!(note.block, hex::encode(&blocks[1].hash));
+        assert_eq!(wallet.synced_height, 2);
+    }
+
+    #[test]
+    fn spends_are_found_by_nullifier_and_undone_by_reorgs() {
+        let mut wallets = Wallets::default();
+        let address = wallets.create_wallet(0).unwrap();
+        let wallet = wallets.get_wallet(&address).unwrap();
+        let (fvk, to) = (wallet.fvk().unwrap(), wallet.z_address().unwrap());
+        let mut blocks = vec![block(vec![payment(None, None, to, 5)], 1)];
+        scan(&blocks, &mut wallets).unwrap();
+
+        let note = wallets.get_wallet(&address).unwrap().notes[0]
+            .try_to_note()
+            .unwrap();
+        blocks.push(block(
+            vec![payment(Some((&fvk, note)), None, stranger(), 5)],
+            2,
+        ));
+        scan(&blocks, &mut wallets).unwrap();
+        let wallet = wallets.get_wallet(&address).unwrap();
+        assert_eq!(wallet.notes[0].spent_in, Some(hex::encode(&blocks[1].hash)));
+
+        // The block spending the note is replaced by one that does not.
+        blocks[1] = block(vec![], 3);
+        scan(&blocks, &mut wallets).unwrap();
+        let wallet = wallets.get_wallet(&address).unwrap();
+        assert_eq!(wallet.notes.len(), 1);
+        assert!(!wallet.notes[0].is_spent());
+    }
+
+    #[test]
+    fn sent_notes_are_recovered_unless_they_pay_the_wallets() {
+        let mut wallets = Wallets::default();
+        let sender = wallets.create_wallet(0).unwrap();
+        let other = wallets.create_wallet(0).unwrap();
+        let fvk = wallets.get_wallet(&sender).unwrap().fvk().unwrap();
+        let ovk = Some(fvk.to_ovk(Scope::External));
+        let own = wallets.get_wallet(&other).unwrap().z_address().unwrap();
+        let change = fvk.address_at(0u32, Scope::Internal);
+        let paid = payment(None, ovk.clone(), stranger(), 3);
+        let blocks = vec![block(
+            vec![
+                paid.clone(),
+                payment(None, ovk.clone(), own, 4),
+                payment(None, ovk, change, 2),
+            ],
+            1,
+        )];
+        scan(&blocks, &mut wallets).unwrap();
+
+        let sent = &wallets.get_wallet(&sender).unwrap().sent;
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].recipient,
+            hex::encode(stranger().to_raw_address_bytes())
+        );
+        assert_eq!((sent[0].value, &sent[0].txid), (3, &hex::encode(&paid.id)));
+        assert_eq!(wallets.get_wallet(&other).unwrap().notes[0].value, 4);
+    }
+}
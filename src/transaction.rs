@@ -1,10 +1,16 @@
+use orchard::bundle::Authorization;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::{collections::HashMap, fmt};
 
-use crate::bundle::Bundle;
+use crate::bundle::{self, Bundle};
 use crate::{
-    blockchain::Blockchain, transaction_input::TXInput, transaction_output::TXOutput, wallet,
+    blockchain::Blockchain,
+    coinselect::{self, Selection, Utxo},
+    error::{Error, Result},
+    transaction_input::TXInput,
+    transaction_output::TXOutput,
+    wallet,
     wallets::Wallets,
 };
 
@@ -16,114 +22,137 @@ pub struct Transaction {
     pub bundle: Bundle,
 }
 
+const TXID_PERSONALIZATION: &[u8] = b"tinyzcash_TxId";
+const TRANSPARENT_PERSONALIZATION: &[u8] = b"tinyzcash_Transp";
+const AUTH_PERSONALIZATION: &[u8] = b"tinyzcash_TxAuth";
+const INPUT_PERSONALIZATION: &[u8] = b"tinyzcash_TxIn";
+
 impl Transaction {
     pub fn set_id(&mut self) {
-        let json_str = serde_json::to_string(self).unwrap();
-        self.id = Sha256::digest(json_str.as_bytes()).to_vec();
+        self.id = self.txid_digest().to_vec();
     }
 
     pub fn is_coinbase(&self) -> bool {
         self.vin.len() == 1 && self.vin[0].txid.is_empty() && self.vin[0].vout == -1
     }
 
-    pub fn serialize(&self) -> String {
-        serde_json::to_string(self).unwrap()
+    /// The transaction id: a digest over the effecting data only (outpoints, outputs and
+    /// the bundle's actions), so re-encoding a signature or proof never changes it.
+    pub fn txid_digest(&self) -> [u8; 32] {
+        txid_digest(&self.transparent_digest(), &self.bundle.effects_digest())
     }
 
-    fn hash(&self) -> Vec<u8> {
-        let mut tx_copy = self.clone();
-        tx_copy.id = vec![];
+    /// Digest over the authorizing data: transparent signatures and public keys, plus the
+    /// bundle's proof and signatures.
+    pub fn auth_digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(AUTH_PERSONALIZATION);
+        for vin in &self.vin {
+            hasher.update((vin.signature.len() as u32).to_le_bytes());
+            hasher.update(&vin.signature);
+            hasher.update((vin.pub_key.len() as u32).to_le_bytes());
+            hasher.update(&vin.pub_key);
+        }
+        hasher.update(self.bundle.auth_digest());
+        hasher.finalize().into()
+    }
 
-        Sha256::digest(tx_copy.serialize().as_bytes()).to_vec()
+    /// The message signed by every Orchard action, and bound to each transparent input's
+    /// own message by [`input_sighash`].
+    pub fn sighash(&self) -> [u8; 32] {
+        self.txid_digest()
     }
 
-    fn trimmed_copy(&self) -> Transaction {
-        let mut inputs = Vec::new();
-        let mut outputs = Vec::new();
+    /// The sighash this transaction will have once `bundle` is attached to it, for signing
+    /// a bundle that is not yet authorized.
+    pub fn shielded_sighash<A: Authorization>(&self, bundle: &orchard::Bundle<A, i64>) -> [u8; 32] {
+        txid_digest(&self.transparent_digest(), &bundle::effects_digest(bundle))
+    }
 
+    fn transparent_digest(&self) -> [u8; 32] {
+        let coinbase = self.is_coinbase();
+        let mut hasher = Sha256::new();
+        hasher.update(TRANSPARENT_PERSONALIZATION);
+        hasher.update((self.vin.len() as u32).to_le_bytes());
         for vin in &self.vin {
-            inputs.push(TXInput {
-                txid: vin.txid.clone(),
-                vout: vin.vout,
-                signature: vec![],
-                pub_key: vec![],
-            });
+            hasher.update(&vin.txid);
+            hasher.update(vin.vout.to_le_bytes());
+            // A coinbase input carries arbitrary data rather than a key, and that data is
+            // what makes otherwise identical coinbase transactions distinct.
+            if coinbase {
+                hasher.update((vin.pub_key.len() as u32).to_le_bytes());
+                hasher.update(&vin.pub_key);
+            }
         }
-
+        hasher.update((self.vout.len() as u32).to_le_bytes());
         for vout in &self.vout {
-            outputs.push(TXOutput {
-                value: vout.value,
-                pub_key_hash: vout.pub_key_hash.clone(),
-            });
-        }
-
-        Transaction {
-            id: self.id.clone(),
-            vin: inputs,
-            vout: outputs,
-            bundle: Bundle::default(),
+            hasher.update(vout.value.to_le_bytes());
+            hasher.update(&vout.pub_key_hash);
         }
+        hasher.finalize().into()
     }
 
     pub fn sign(
         &mut self,
         private_key: secp256k1::SecretKey,
         prev_txs: &HashMap<String, Transaction>,
-    ) {
+    ) -> Result<()> {
         if self.is_coinbase() {
-            return;
+            return Ok(());
         }
 
-        for vin in &self.vin {
-            if prev_txs
-                .get(&hex::encode(vin.txid.as_slice()))
-                .unwrap()
-                .id
-                .is_empty()
-            {
-                panic!("ERROR: Previous transaction is not correct");
-            }
-        }
-        let mut tx_copy = self.trimmed_copy();
-
-        for in_id in 0..tx_copy.vin.len() {
-            let mut vin = tx_copy.vin[in_id].clone();
-            let prev_tx = prev_txs.get(&hex::encode(&vin.txid)).unwrap();
-            vin.signature = vec![];
-            vin.pub_key = prev_tx.vout[vin.vout as usize].pub_key_hash.clone();
-
-            tx_copy.vin[in_id] = vin;
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[in_id].pub_key = vec![];
-
-            let tx_copy_message = secp256k1::Message::from_digest_slice(&tx_copy.id).unwrap();
-            let context = secp256k1::Secp256k1::new();
-            let signature = context.sign_ecdsa(&tx_copy_message, &private_key);
-            let sig = signature.serialize_compact();
+        self.sign_inputs(&private_key, self.sighash(), prev_txs)
+    }
 
-            self.vin[in_id].signature = sig.to_vec();
+    /// Signs each input over its own [`input_sighash`] derived from `sighash`, which may
+    /// be computed for a bundle that is only attached to the transaction later.
+    pub fn sign_inputs(
+        &mut self,
+        private_key: &secp256k1::SecretKey,
+        sighash: [u8; 32],
+        prev_txs: &HashMap<String, Transaction>,
+    ) -> Result<()> {
+        let context = secp256k1::Secp256k1::new();
+        for vin in self.vin.iter_mut() {
+            let prev_out = prev_output(prev_txs, vin).ok_or_else(|| {
+                Error::InvalidTransaction(format!(
+                    "previous output {}:{} not found",
+                    hex::encode(&vin.txid),
+                    vin.vout
+                ))
+            })?;
+            let message = secp256k1::Message::from_digest(input_sighash(&sighash, vin, prev_out));
+            let signature = context.sign_ecdsa(&message, private_key);
+            vin.signature = signature.serialize_compact().to_vec();
         }
+        Ok(())
     }
 
     pub fn verify(&self, prev_txs: &HashMap<String, Transaction>) -> bool {
-        let mut tx_copy = self.trimmed_copy();
+        let sighash = self.sighash();
         let secp = secp256k1::Secp256k1::new();
 
-        for (in_id, vin) in self.vin.iter().enumerate() {
-            let prev_tx = &prev_txs[&hex::encode(&vin.txid)];
-            tx_copy.vin[in_id].signature = vec![];
-            tx_copy.vin[in_id].pub_key = prev_tx.vout[vin.vout as usize].pub_key_hash.clone();
-            tx_copy.id = tx_copy.hash();
-            tx_copy.vin[in_id].pub_key = vec![];
-
-            let tx_copy_message = secp256k1::Message::from_digest_slice(&tx_copy.id).unwrap();
-
-            let pk =
-                secp256k1::PublicKey::from_slice(hex::decode(&vin.pub_key).unwrap().as_slice())
-                    .unwrap();
+        for vin in &self.vin {
+            let prev_out = match prev_output(prev_txs, vin) {
+                Some(out) if vin.use_key(&out.pub_key_hash) => out,
+                _ => return false,
+            };
+            let message = secp256k1::Message::from_digest(input_sighash(&sighash, vin, prev_out));
 
-            let sig = secp256k1::ecdsa::Signature::from_compact(&vin.signature).unwrap();
-            if secp.verify_ecdsa(&tx_copy_message, &sig, &pk).is_err() {
+            // Inputs may come from a partially created transaction read from a file, so
+            // malformed keys and signatures fail verification rather than panicking.
+            let pk = match hex::decode(&vin.pub_key)
+                .ok()
+                .and_then(|pk| secp256k1::PublicKey::from_slice(&pk).ok())
+            {
+                Some(pk) => pk,
+                None => return false,
+            };
+            let sig = match secp256k1::ecdsa::Signature::from_compact(&vin.signature) {
+                Ok(sig) => sig,
+                Err(_) => return false,
+            };
+            if secp.verify_ecdsa(&message, &sig, &pk).is_err() {
                 return false;
             }
         }
@@ -132,6 +161,38 @@ impl Transaction {
     }
 }
 
+/// The message transparent input `vin` signs: the transaction's `sighash` bound to the
+/// input's outpoint and to the value and key hash of the output it spends, so a signature
+/// authorizes exactly one input and commits to the amount it spends.
+pub fn input_sighash(sighash: &[u8; 32], vin: &TXInput, prev_out: &TXOutput) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(INPUT_PERSONALIZATION);
+    hasher.update(sighash);
+    hasher.update(&vin.txid);
+    hasher.update(vin.vout.to_le_bytes());
+    hasher.update(prev_out.value.to_le_bytes());
+    hasher.update((prev_out.pub_key_hash.len() as u32).to_le_bytes());
+    hasher.update(&prev_out.pub_key_hash);
+    hasher.finalize().into()
+}
+
+/// The output `vin` spends, if its transaction is among `prev_txs`.
+fn prev_output<'a>(
+    prev_txs: &'a HashMap<String, Transaction>,
+    vin: &TXInput,
+) -> Option<&'a TXOutput> {
+    let prev_tx = prev_txs.get(&hex::encode(&vin.txid))?;
+    prev_tx.vout.get(usize::try_from(vin.vout).ok()?)
+}
+
+fn txid_digest(transparent_digest: &[u8; 32], bundle_digest: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(TXID_PERSONALIZATION);
+    hasher.update(transparent_digest);
+    hasher.update(bundle_digest);
+    hasher.finalize().into()
+}
+
 impl fmt::Display for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         _ = writeln!(f, "{}", hex::encode(&self.id));
@@ -146,14 +207,14 @@ impl fmt::Display for Transaction {
     }
 }
 
-pub fn new_coinbase_tx(to: &str, data: &str, value: i64) -> Transaction {
+pub fn new_coinbase_tx(to: &str, data: &str, value: i64) -> Result<Transaction> {
     let txin = TXInput {
         txid: vec![],
         vout: -1,
         signature: vec![],
         pub_key: data.as_bytes().to_vec(),
     };
-    let txout = TXOutput::new(value, to);
+    let txout = TXOutput::new(value, to)?;
     let mut tx = Transaction {
         id: vec![],
         vin: vec![txin],
@@ -162,38 +223,105 @@ pub fn new_coinbase_tx(to: &str, data: &str, value: i64) -> Transaction {
     };
     tx.set_id();
 
-    tx
+    Ok(tx)
 }
 
-pub fn new_utxo_transaction(from: String, to: String, amount: i64, bc: &Blockchain) -> Transaction {
-    let mut inputs = Vec::new();
-    let mut outputs = Vec::new();
+pub fn new_utxo_transaction(
+    from: String,
+    to: String,
+    amount: i64,
+    selection: &Selection,
+    bc: &Blockchain,
+) -> Result<Transaction> {
+    let wallets = Wallets::new()?;
+    let wallet = wallets
+        .get_wallet(&from)
+        .ok_or_else(|| Error::UnknownAddress(from.clone()))?;
+    let private_key = wallet.secret_key()?;
+    let mut tx = new_funded_transaction(
+        &from,
+        vec![TXOutput::new(amount, &to)?],
+        amount,
+        selection,
+        bc,
+    )?;
+    bc.sign_transaction(&mut tx, private_key)?;
+    Ok(tx)
+}
 
-    let wallets = Wallets::new();
-    let wallet = wallets.get_wallet(&from).unwrap();
+/// Builds an unsigned transaction spending enough of `from`'s outputs to cover `amount`,
+/// paying `outputs` and returning the excess to `from`. `amount` may exceed the sum of
+/// `outputs` when the difference flows into a shielded bundle. Outputs locked with
+/// `lockunspent` are never picked unless pinned in `selection`, which may pin an output
+/// only once.
+pub fn new_funded_transaction(
+    from: &str,
+    mut outputs: Vec<TXOutput>,
+    amount: i64,
+    selection: &Selection,
+    bc: &Blockchain,
+) -> Result<Transaction> {
+    if amount <= 0 {
+        return Err(Error::InvalidInput(format!(
+            "amount must be positive, got {}",
+            amount
+        )));
+    }
+    if outputs.iter().any(|out| out.value < 0) {
+        return Err(Error::InvalidInput(
+            "output values must not be negative".to_string(),
+        ));
+    }
+    selection.check_pins()?;
+
+    let wallets = Wallets::new()?;
+    let wallet = wallets
+        .get_wallet(from)
+        .ok_or_else(|| Error::UnknownAddress(from.to_string()))?;
     let pub_key_hash = wallet::hash_pub_key(wallet.public_key.as_bytes());
 
-    let (acc, valid_outputs) = bc.find_spendable_outputs(&pub_key_hash, amount);
-    if acc < amount {
-        panic!("ERROR: Not enough funds");
+    let utxos = bc.find_utxos(&pub_key_hash)?;
+    let mut selected = vec![];
+    for outpoint in &selection.pinned {
+        let utxo = utxos
+            .iter()
+            .find(|u| u.outpoint == *outpoint)
+            .ok_or_else(|| {
+                Error::InvalidInput(format!(
+                    "{} is not an unspent output of '{}'",
+                    outpoint, from
+                ))
+            })?;
+        selected.push(utxo.clone());
     }
-
-    for (txid, outs) in valid_outputs {
-        let tx_id = hex::decode(txid.clone()).unwrap();
-        for out in outs {
-            let input = TXInput {
-                txid: tx_id.clone(),
-                vout: out,
-                signature: vec![],
-                pub_key: wallet.public_key.clone().into_bytes(),
-            };
-            inputs.push(input);
-        }
+    let pinned: i64 = selected.iter().map(|u| u.value).sum();
+    if pinned < amount {
+        let candidates: Vec<Utxo> = utxos
+            .into_iter()
+            .filter(|u| !selection.pinned.contains(&u.outpoint))
+            .filter(|u| !wallets.is_locked_output(&u.outpoint))
+            .collect();
+        let more = coinselect::select(&candidates, amount - pinned, selection.strategy)
+            .ok_or_else(|| Error::InsufficientFunds {
+                available: candidates.iter().map(|u| u.value as u64).sum::<u64>() + pinned as u64,
+                required: amount as u64,
+            })?;
+        selected.extend(more);
     }
+    let acc: i64 = selected.iter().map(|u| u.value).sum();
+
+    let inputs = selected
+        .into_iter()
+        .map(|u| TXInput {
+            txid: hex::decode(&u.outpoint.txid).unwrap(),
+            vout: u.outpoint.vout,
+            signature: vec![],
+            pub_key: wallet.public_key.clone().into_bytes(),
+        })
+        .collect();
 
-    outputs.push(TXOutput::new(amount, &to));
     if acc > amount {
-        outputs.push(TXOutput::new(acc - amount, &from));
+        outputs.push(TXOutput::new(acc - amount, from)?);
     }
 
     let mut tx = Transaction {
@@ -203,6 +331,48 @@ pub fn new_utxo_transaction(from: String, to: String, amount: i64, bc: &Blockcha
         bundle: Bundle::default(),
     };
     tx.set_id();
-    bc.sign_transaction(&mut tx, wallet.private_key.clone());
-    tx
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::tests::wallet;
+    use crate::wallets;
+
+    #[test]
+    fn resigning_an_input_keeps_the_txid() {
+        let me = wallet(1);
+        let prev = new_coinbase_tx(&me.get_address(), "coinbase", 10).unwrap();
+        let mut tx = Transaction {
+            vin: vec![TXInput {
+                txid: prev.id.clone(),
+                vout: 0,
+                signature: vec![],
+                pub_key: me.public_key.clone().into_bytes(),
+            }],
+            vout: vec![TXOutput::new(10, &wallet(2).get_address()).unwrap()],
+            ..Default::default()
+        };
+        tx.set_id();
+        let prev_out = prev.vout[0].clone();
+        let prev_txs = HashMap::from([(hex::encode(&prev.id), prev)]);
+        let key = wallets::parse_private_key(&me.secret_key().unwrap()).unwrap();
+        tx.sign(key, &prev_txs).unwrap();
+        assert!(tx.verify(&prev_txs));
+        let (id, auth) = (tx.id.clone(), tx.auth_digest());
+
+        // A different signature over the same message authorizes the same effects.
+        let message = input_sighash(&tx.sighash(), &tx.vin[0], &prev_out);
+        let signature = secp256k1::Secp256k1::new().sign_ecdsa_with_noncedata(
+            &secp256k1::Message::from_digest(message),
+            &key,
+            &[7; 32],
+        );
+        assert_ne!(tx.vin[0].signature, signature.serialize_compact());
+        tx.vin[0].signature = signature.serialize_compact().to_vec();
+        assert!(tx.verify(&prev_txs));
+        assert_eq!(tx.txid_digest().to_vec(), id);
+        assert_ne!(tx.auth_digest(), auth);
+    }
 }
@@ -2,6 +2,8 @@ use std::fmt;
 
 use serde::{Deserialize, Serialize};
 
+use crate::{error::Result, wallet};
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct TXOutput {
     pub value: i64,
@@ -9,18 +11,18 @@ pub struct TXOutput {
 }
 
 impl TXOutput {
-    pub fn new(value: i64, address: &str) -> Self {
+    pub fn new(value: i64, address: &str) -> Result<Self> {
         let mut txo = TXOutput {
             value,
             pub_key_hash: vec![],
         };
-        txo.lock(address.as_bytes().to_vec());
-        txo
+        txo.lock(address)?;
+        Ok(txo)
     }
 
-    pub fn lock(&mut self, address: Vec<u8>) {
-        let pub_key_hash = bs58::decode(&address).into_vec().unwrap();
-        self.pub_key_hash = pub_key_hash[1..pub_key_hash.len() - 4].to_vec();
+    pub fn lock(&mut self, address: &str) -> Result<()> {
+        self.pub_key_hash = wallet::decode_address(address)?;
+        Ok(())
     }
 
     pub fn is_locked_with_key(&self, pub_key_hash: &Vec<u8>) -> bool {
@@ -1,15 +1,16 @@
 use orchard::{bundle::Authorized, circuit::VerifyingKey, Bundle};
 
-pub fn verify_bundle(bundle: &Bundle<Authorized, i64>) {
+pub fn verify_bundle(bundle: &Bundle<Authorized, i64>, sighash: &[u8; 32]) -> bool {
     let vk = VerifyingKey::build();
-    assert!(matches!(bundle.verify_proof(&vk), Ok(())));
-    let sighash: [u8; 32] = bundle.commitment().into();
-    let bvk = bundle.binding_validating_key();
+    if bundle.verify_proof(&vk).is_err() {
+        return false;
+    }
     for action in bundle.actions() {
-        assert_eq!(action.rk().verify(&sighash, action.authorization()), Ok(()));
+        if action.rk().verify(sighash, action.authorization()).is_err() {
+            return false;
+        }
     }
-    assert_eq!(
-        bvk.verify(&sighash, bundle.authorization().binding_signature()),
-        Ok(())
-    );
+    let bvk = bundle.binding_validating_key();
+    bvk.verify(sighash, bundle.authorization().binding_signature())
+        .is_ok()
 }
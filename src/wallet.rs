@@ -1,97 +1,443 @@
+use crate::crypto;
+use crate::encoding::{self, ViewingKey};
+use crate::error::{Error, Result};
+use bip32::{DerivationPath, XPrv};
 use orchard::keys::FullViewingKey;
-use orchard::note::{Nullifier, RandomSeed};
+use orchard::note::{ExtractedNoteCommitment, Nullifier, RandomSeed};
 use orchard::value::NoteValue;
 use orchard::{keys, Address};
-use rand::rngs::OsRng as randRng;
-use rand::RngCore;
 use ripemd::{Digest as RipemdDigest, Ripemd160};
-use secp256k1::rand::rngs::OsRng;
-use secp256k1::Secp256k1;
+use secp256k1::{PublicKey, Secp256k1, SecretKey};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 
 const VERSION: u8 = 0x00;
-pub(crate) const CHECKSUM_LENGTH: usize = 4;
+/// SLIP-44 coin type used for both BIP-44 and ZIP-32 derivation (testnet).
+const COIN_TYPE: u32 = 1;
+const WATCH_ONLY: &str = "wallet is watch-only and cannot sign";
+pub const CHECKSUM_LENGTH: usize = 4;
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Wallet {
     pub private_key: String,
     pub public_key: String,
     pub spend_key: String,
+    /// Account index the keys were derived at from the wallet file's mnemonic. Wallets
+    /// created before mnemonics, and watch-only wallets, have none.
+    #[serde(default)]
+    pub account: Option<u32>,
+    /// Bech32m viewing key of a watch-only wallet, which has no spending keys, or of an
+    /// encrypted wallet, so it can still scan while locked.
+    #[serde(default)]
+    pub viewing_key: String,
+    /// The private and spending keys encrypted under the wallet file's passphrase. While
+    /// set, the plaintext keys are only present when the file is unlocked.
+    #[serde(default)]
+    pub encrypted_keys: String,
+    /// Chain height when the wallet was created or imported; it received nothing earlier.
+    #[serde(default)]
+    pub birthday: u64,
     pub notes: Vec<Note>,
+    /// Diversifier indices of the shielded addresses issued after the default one at 0.
+    #[serde(default)]
+    pub diversifiers: Vec<u32>,
+    /// Outputs this wallet sent, recovered from the chain with its outgoing viewing key.
+    #[serde(default)]
+    pub sent: Vec<SentNote>,
+    /// Number of blocks already scanned for notes.
+    #[serde(default)]
+    pub synced_height: u64,
+    /// Hex hash of the last scanned block, used to detect reorgs.
+    #[serde(default)]
+    pub synced_hash: String,
 }
+/// Current layout of [`Note`]. Version 0 records stored the note's rho under `nf` and
+/// an `internal` flag in place of the recipient; [`Wallet::migrate_notes`] upgrades them.
+pub const NOTE_VERSION: u8 = 1;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Note {
+    #[serde(default)]
+    pub version: u8,
+    /// Hex of the raw 43-byte recipient address.
+    #[serde(default)]
+    pub recipient: String,
     pub value: u64,
+    #[serde(alias = "nf")]
+    pub rho: [u8; 32],
     pub rseed: [u8; 32],
-    pub nf: [u8; 32],
+    /// The note's extracted commitment, its leaf in the note commitment tree.
+    #[serde(default)]
+    pub cmx: [u8; 32],
+    /// Position of the note's commitment in the chain's note commitment tree.
+    #[serde(default)]
+    pub position: u64,
+    /// Hex of the decrypted 512-byte memo.
+    #[serde(default)]
+    pub memo: String,
+    /// The note's nullifier, revealed on chain when it is spent.
+    #[serde(default)]
+    pub nullifier: [u8; 32],
+    /// Hex id of the transaction the note arrived in.
+    #[serde(default)]
+    pub txid: String,
+    /// Hex hash of the block the note arrived in.
+    #[serde(default)]
+    pub block: String,
+    /// Hex hash of the block that spent the note, if any.
+    #[serde(default)]
+    pub spent_in: Option<String>,
+    /// Version 0 only: whether the note was sent to the internal (change) address.
+    #[serde(default, skip_serializing)]
+    internal: bool,
+}
+
+/// An output sent by the wallet, as recovered with its outgoing viewing key.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SentNote {
+    /// Hex of the raw 43-byte recipient address.
+    pub recipient: String,
+    pub value: u64,
+    /// Hex of the 512-byte memo.
+    pub memo: String,
+    /// The output's extracted commitment, identifying it on chain.
+    pub cmx: [u8; 32],
+    /// Hex id of the transaction that sent the output.
+    pub txid: String,
+    /// Hex hash of the block the transaction was mined in.
+    pub block: String,
+}
+
+impl SentNote {
+    pub fn new(note: &orchard::Note, memo: &[u8; 512], txid: &[u8], block: &str) -> Self {
+        SentNote {
+            recipient: hex::encode(note.recipient().to_raw_address_bytes()),
+            value: note.value().inner(),
+            memo: hex::encode(memo),
+            cmx: ExtractedNoteCommitment::from(note.commitment()).to_bytes(),
+            txid: hex::encode(txid),
+            block: block.to_string(),
+        }
+    }
 }
 
 impl Note {
-    pub fn to_note(&self, addr: orchard::Address) -> orchard::Note {
-        let old_nf = Nullifier::from_bytes(&self.nf).unwrap();
-        orchard::Note::from_parts(
-            addr,
+    /// `fvk` is `None` for watch-only wallets holding only an incoming viewing key, whose
+    /// notes then have no known nullifier.
+    pub fn new(
+        note: &orchard::Note,
+        fvk: Option<&FullViewingKey>,
+        memo: &[u8; 512],
+        txid: &[u8],
+        position: u64,
+    ) -> Self {
+        Note {
+            version: NOTE_VERSION,
+            recipient: hex::encode(note.recipient().to_raw_address_bytes()),
+            value: note.value().inner(),
+            rho: note.rho().to_bytes(),
+            rseed: *note.rseed().as_bytes(),
+            cmx: ExtractedNoteCommitment::from(note.commitment()).to_bytes(),
+            position,
+            memo: hex::encode(memo),
+            nullifier: fvk
+                .map(|fvk| note.nullifier(fvk).to_bytes())
+                .unwrap_or_default(),
+            txid: hex::encode(txid),
+            block: String::new(),
+            spent_in: None,
+            internal: false,
+        }
+    }
+
+    pub fn is_spent(&self) -> bool {
+        self.spent_in.is_some()
+    }
+
+    /// Rebuilds the orchard note, failing if the record is malformed, as one read from a
+    /// partial transaction may be.
+    pub fn try_to_note(&self) -> Result<orchard::Note> {
+        let malformed = || Error::InvalidInput(format!("malformed note {}", hex::encode(self.cmx)));
+        let rho = Option::from(Nullifier::from_bytes(&self.rho)).ok_or_else(malformed)?;
+        let recipient = parse_raw_address(&self.recipient).ok_or_else(malformed)?;
+        let rseed = Option::from(RandomSeed::from_bytes(self.rseed, &rho)).ok_or_else(malformed)?;
+        Option::from(orchard::Note::from_parts(
+            recipient,
             NoteValue::from_raw(self.value),
-            old_nf,
-            RandomSeed::from_bytes(self.rseed, &old_nf).unwrap(),
-        )
-        .unwrap()
+            rho,
+            rseed,
+        ))
+        .ok_or_else(malformed)
     }
 }
 
 impl Wallet {
-    pub fn new() -> Wallet {
-        let secp = Secp256k1::new();
-        let (private_key, public_key) = secp.generate_keypair(&mut OsRng);
+    /// Derives the wallet for `account` from a BIP-39 seed: the transparent key at the
+    /// BIP-44 path m/44'/coin'/account'/0/0 and the Orchard key of ZIP-32 account `account`.
+    pub fn from_seed(seed: &[u8; 64], account: u32) -> Result<Wallet> {
+        let underivable =
+            |e: String| Error::InvalidInput(format!("cannot derive account {}: {}", account, e));
+        let path: DerivationPath = format!("m/44'/{}'/{}'/0/0", COIN_TYPE, account)
+            .parse()
+            .map_err(|e: bip32::Error| underivable(e.to_string()))?;
+        let xprv = XPrv::derive_from_path(seed, &path).map_err(|e| underivable(e.to_string()))?;
+        let private_key =
+            SecretKey::from_slice(&xprv.to_bytes()).map_err(|e| underivable(e.to_string()))?;
+
+        let spend_key = keys::SpendingKey::from_zip32_seed(seed, COIN_TYPE, account)
+            .map_err(|e| underivable(e.to_string()))?;
 
-        let mut rng = randRng;
-        let mut random_bytes = [0u8; 32];
-        rng.fill_bytes(&mut random_bytes);
-        let spend_key = keys::SpendingKey::from_zip32_seed(&random_bytes, 0, 0).unwrap();
+        let mut wallet = Wallet::from_keys(Some(&private_key), Some(&spend_key));
+        wallet.account = Some(account);
+        Ok(wallet)
+    }
 
+    /// Creates a wallet holding either or both of a transparent and a shielded key, as
+    /// given to the import commands.
+    pub fn from_keys(
+        private_key: Option<&SecretKey>,
+        spend_key: Option<&keys::SpendingKey>,
+    ) -> Wallet {
+        let public_key =
+            private_key.map(|k| PublicKey::from_secret_key(&Secp256k1::new(), k).to_string());
         Wallet {
-            private_key: hex::encode(private_key.secret_bytes()),
-            public_key: public_key.to_string(),
-            spend_key: hex::encode(spend_key.to_bytes()),
+            private_key: private_key.map_or(String::new(), |k| hex::encode(k.secret_bytes())),
+            public_key: public_key.unwrap_or_default(),
+            spend_key: spend_key.map_or(String::new(), |k| hex::encode(k.to_bytes())),
+            account: None,
+            viewing_key: String::new(),
+            encrypted_keys: String::new(),
+            birthday: 0,
             notes: vec![],
+            diversifiers: vec![],
+            sent: vec![],
+            synced_height: 0,
+            synced_hash: String::new(),
+        }
+    }
+
+    /// Creates a watch-only wallet from a Bech32m full or incoming viewing key. It scans
+    /// and reports balances but cannot sign.
+    pub fn watch_only(viewing_key: &str) -> Result<Wallet> {
+        encoding::decode_viewing_key(viewing_key)?;
+        let mut wallet = Wallet::from_keys(None, None);
+        wallet.viewing_key = viewing_key.to_string();
+        Ok(wallet)
+    }
+
+    /// Sets the height the wallet's history starts at, where scanning for it begins.
+    pub fn set_birthday(&mut self, height: u64) {
+        self.birthday = height;
+        self.synced_height = height;
+        self.synced_hash = String::new();
+    }
+
+    /// Whether the wallet holds no secrets at all, only a viewing key.
+    pub fn is_watch_only(&self) -> bool {
+        self.private_key.is_empty() && self.spend_key.is_empty() && self.encrypted_keys.is_empty()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.private_key.is_empty() && self.spend_key.is_empty() && !self.encrypted_keys.is_empty()
+    }
+
+    pub fn has_transparent(&self) -> bool {
+        !self.public_key.is_empty()
+    }
+
+    pub fn has_shielded(&self) -> bool {
+        !self.ivks().is_empty()
+    }
+
+    /// Encrypts the wallet's keys under `key`, keeping its full viewing key in the clear.
+    pub fn encrypt(&mut self, key: &[u8; 32]) {
+        if self.is_watch_only() {
+            return;
+        }
+        if let Some(fvk) = self.fvk() {
+            self.viewing_key = encoding::encode_fvk(&fvk);
+        }
+        let secrets = serde_json::to_vec(&(&self.private_key, &self.spend_key)).unwrap();
+        self.encrypted_keys = crypto::encrypt(key, &secrets);
+    }
+
+    /// Restores the plaintext keys of an encrypted wallet.
+    pub fn decrypt(&mut self, key: &[u8; 32]) -> Result<()> {
+        if self.encrypted_keys.is_empty() {
+            return Ok(());
+        }
+        let secrets = crypto::decrypt(key, &self.encrypted_keys)?;
+        let (private_key, spend_key): (String, String) = serde_json::from_slice(&secrets)?;
+        self.private_key = private_key;
+        self.spend_key = spend_key;
+        Ok(())
+    }
+
+    /// Drops the plaintext keys of an encrypted wallet before it is written out.
+    pub fn strip_secrets(&mut self) {
+        if !self.encrypted_keys.is_empty() {
+            self.private_key.clear();
+            self.spend_key.clear();
         }
     }
 
+    /// The hex secp256k1 key signing the wallet's transparent inputs.
+    pub fn secret_key(&self) -> Result<String> {
+        if self.is_locked() {
+            return Err(Error::WalletLocked);
+        }
+        if self.is_watch_only() {
+            return Err(Error::MissingKey(WATCH_ONLY.to_string()));
+        }
+        if self.private_key.is_empty() {
+            return Err(Error::MissingKey(
+                "wallet has no transparent key".to_string(),
+            ));
+        }
+        Ok(self.private_key.clone())
+    }
+
     pub fn get_address(&self) -> String {
-        let pub_key_hash = hash_pub_key(self.public_key.as_bytes());
-        let mut versioned_payload = vec![VERSION];
-        versioned_payload.extend_from_slice(&pub_key_hash);
-        let checksum = checksum(&versioned_payload);
-        versioned_payload.extend_from_slice(&checksum);
-        bs58::encode(&versioned_payload).into_string()
+        address_of_pub_key_hash(&hash_pub_key(self.public_key.as_bytes()))
+    }
+
+    /// The wallet's default shielded address, or `None` if it has no shielded keys.
+    pub fn get_z_address(&self) -> Option<String> {
+        self.z_address().map(|addr| encoding::encode_address(&addr))
     }
 
-    pub fn get_z_address(&self) -> String {
-        let addr = self.z_address();
-        hex::encode(addr.to_raw_address_bytes())
+    pub fn sk(&self) -> Result<keys::SpendingKey> {
+        if self.is_locked() {
+            return Err(Error::WalletLocked);
+        }
+        if self.is_watch_only() {
+            return Err(Error::MissingKey(WATCH_ONLY.to_string()));
+        }
+        if self.spend_key.is_empty() {
+            return Err(Error::MissingKey(
+                "wallet has no shielded spending key".to_string(),
+            ));
+        }
+        let bytes: [u8; 32] = hex::decode(&self.spend_key)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| Error::InvalidInput("malformed spending key".to_string()))?;
+        Option::from(keys::SpendingKey::from_bytes(bytes))
+            .ok_or_else(|| Error::InvalidInput("malformed spending key".to_string()))
     }
 
-    pub fn sk(&self) -> keys::SpendingKey {
-        let spend_key = hex::decode(&self.spend_key).unwrap();
-        let spend_key: Result<[u8; 32], _> = spend_key.try_into();
-        keys::SpendingKey::from_bytes(spend_key.unwrap()).unwrap()
+    /// The full viewing key, unless this is a watch-only wallet imported from an incoming
+    /// viewing key. Locked wallets read it from their stored viewing key. Without it
+    /// nullifiers, and so spends, cannot be detected.
+    pub fn fvk(&self) -> Option<FullViewingKey> {
+        if !self.spend_key.is_empty() {
+            return self.sk().ok().map(|sk| FullViewingKey::from(&sk));
+        }
+        match encoding::decode_viewing_key(&self.viewing_key) {
+            Ok(ViewingKey::Full(fvk)) => Some(fvk),
+            _ => None,
+        }
     }
 
-    pub fn z_address(&self) -> Address {
-        let fvk = FullViewingKey::from(&self.sk());
-        fvk.address_at(0u32, keys::Scope::External)
+    /// Whether spends of the wallet's shielded notes are detected. A wallet imported from
+    /// an incoming viewing key only sees notes arrive, so all of them look unspent.
+    pub fn detects_spends(&self) -> bool {
+        !self.has_shielded() || self.fvk().is_some()
+    }
+
+    /// The incoming viewing keys to scan with: external and internal scopes when the full
+    /// viewing key is known, only the external one otherwise.
+    pub fn ivks(&self) -> Vec<keys::IncomingViewingKey> {
+        if let Some(fvk) = self.fvk() {
+            return vec![
+                fvk.to_ivk(keys::Scope::External),
+                fvk.to_ivk(keys::Scope::Internal),
+            ];
+        }
+        match encoding::decode_viewing_key(&self.viewing_key) {
+            Ok(ViewingKey::Incoming(ivk)) => vec![ivk],
+            _ => vec![],
+        }
+    }
+
+    /// Upgrades version 0 note records, deriving the recipient, commitment and real
+    /// nullifier from the wallet's full viewing key.
+    pub fn migrate_notes(&mut self) {
+        let fvk = match self.fvk() {
+            Some(fvk) => fvk,
+            None => return,
+        };
+        for note in self.notes.iter_mut().filter(|n| n.version == 0) {
+            let scope = if note.internal {
+                keys::Scope::Internal
+            } else {
+                keys::Scope::External
+            };
+            note.recipient = hex::encode(fvk.address_at(0u32, scope).to_raw_address_bytes());
+            let n = match note.try_to_note() {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            note.cmx = ExtractedNoteCommitment::from(n.commitment()).to_bytes();
+            note.nullifier = n.nullifier(&fvk).to_bytes();
+            note.version = NOTE_VERSION;
+        }
+    }
+
+    pub fn z_address(&self) -> Option<Address> {
+        Some(self.ivks().first()?.address_at(0u32))
+    }
+
+    /// Issues the shielded address at the next unused diversifier index, or `None` if the
+    /// wallet has no shielded keys. Every diversified address shares the wallet's incoming
+    /// viewing key, so scanning finds payments to any of them.
+    pub fn new_z_address(&mut self) -> Option<Address> {
+        let ivk = self.ivks().into_iter().next()?;
+        let index = self.diversifiers.iter().max().map_or(1, |i| i + 1);
+        self.diversifiers.push(index);
+        Some(ivk.address_at(index))
     }
 }
 
-pub fn validate_address(address: &String) -> bool {
-    let pub_key_hash = bs58::decode(address).into_vec().unwrap();
-    let actual_checksum = &pub_key_hash[pub_key_hash.len() - CHECKSUM_LENGTH..];
-    let version = pub_key_hash[0];
-    let pub_key_hash = &pub_key_hash[1..pub_key_hash.len() - CHECKSUM_LENGTH];
-    let target_checksum = checksum([&[version], pub_key_hash].concat().as_slice());
+/// Encodes the transparent address outputs locked to `pub_key_hash` pay.
+pub fn address_of_pub_key_hash(pub_key_hash: &[u8]) -> String {
+    let mut versioned_payload = vec![VERSION];
+    versioned_payload.extend_from_slice(pub_key_hash);
+    let checksum = checksum(&versioned_payload);
+    versioned_payload.extend_from_slice(&checksum);
+    bs58::encode(&versioned_payload).into_string()
+}
 
-    actual_checksum == target_checksum
+pub fn validate_address(address: &str) -> bool {
+    decode_address(address).is_ok()
+}
+
+/// Returns the public key hash a transparent address pays, checking its checksum.
+pub fn decode_address(address: &str) -> Result<Vec<u8>> {
+    let invalid = || Error::InvalidAddress(address.to_string());
+    let decoded = match bs58::decode(address).into_vec() {
+        Ok(decoded) if decoded.len() > CHECKSUM_LENGTH => decoded,
+        _ => return Err(invalid()),
+    };
+    let (payload, actual_checksum) = decoded.split_at(decoded.len() - CHECKSUM_LENGTH);
+    if checksum(payload) != actual_checksum {
+        return Err(invalid());
+    }
+    Ok(payload[1..].to_vec())
+}
+
+/// Parses a Bech32m Orchard address, or the hex of its raw bytes used by older wallets.
+pub fn parse_z_address(address: &str) -> Result<Address> {
+    if let Some(addr) = parse_raw_address(address) {
+        return Ok(addr);
+    }
+    encoding::decode_address(address)
+}
+
+/// Parses the hex of a raw 43-byte Orchard address, as stored in note records.
+fn parse_raw_address(address: &str) -> Option<Address> {
+    let bytes: [u8; 43] = hex::decode(address).ok()?.try_into().ok()?;
+    Option::from(Address::from_raw_address_bytes(&bytes))
 }
 
 pub fn hash_pub_key(pub_key: &[u8]) -> Vec<u8> {
@@ -107,3 +453,172 @@ pub fn checksum(payload: &[u8]) -> Vec<u8> {
 
     second_sha[0..CHECKSUM_LENGTH].to_vec()
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::transaction_output::TXOutput;
+
+    /// A wallet with fixed transparent and shielded keys.
+    pub(crate) fn wallet(seed: u8) -> Wallet {
+        let private_key = SecretKey::from_slice(&[seed; 32]).unwrap();
+        let spend_key = keys::SpendingKey::from_bytes([seed; 32]).unwrap();
+        Wallet::from_keys(Some(&private_key), Some(&spend_key))
+    }
+
+    /// Records a note of `value` paid to `recipient` in transaction `txid`, as the scanner
+    /// does on decrypting it with `fvk`.
+    pub(crate) fn received_note(
+        fvk: &FullViewingKey,
+        recipient: Address,
+        value: u64,
+        txid: &[u8],
+    ) -> Note {
+        let rho = Nullifier::from_bytes(&[0; 32]).unwrap();
+        let rseed = (0..=u8::MAX)
+            .find_map(|b| Option::from(RandomSeed::from_bytes([b; 32], &rho)))
+            .unwrap();
+        let note =
+            orchard::Note::from_parts(recipient, NoteValue::from_raw(value), rho, rseed).unwrap();
+        Note::new(&note, Some(fvk), &[0xf6; 512], txid, 0)
+    }
+
+    #[test]
+    fn transparent_addresses_round_trip() {
+        let wallet = wallet(1);
+        let address = wallet.get_address();
+        assert!(validate_address(&address));
+        assert_eq!(
+            decode_address(&address).unwrap(),
+            hash_pub_key(wallet.public_key.as_bytes())
+        );
+    }
+
+    #[test]
+    fn bad_addresses_are_invalid() {
+        let mut corrupted = wallet(1).get_address();
+        let last = if corrupted.ends_with('1') { '2' } else { '1' };
+        corrupted.pop();
+        corrupted.push(last);
+        for address in ["", "0", "not an address", corrupted.as_str()] {
+            assert!(matches!(
+                decode_address(address),
+                Err(Error::InvalidAddress(a)) if a == address
+            ));
+            assert!(matches!(
+                TXOutput::new(1, address),
+                Err(Error::InvalidAddress(_))
+            ));
+            assert!(matches!(
+                parse_z_address(address),
+                Err(Error::InvalidAddress(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn shielded_addresses_round_trip() {
+        let wallet = wallet(2);
+        let zaddr = wallet.get_z_address().unwrap();
+        assert_eq!(
+            parse_z_address(&zaddr).unwrap(),
+            wallet.z_address().unwrap()
+        );
+        // Older wallets stored the hex of the raw address bytes.
+        let raw = hex::encode(wallet.z_address().unwrap().to_raw_address_bytes());
+        assert_eq!(parse_z_address(&raw).unwrap(), wallet.z_address().unwrap());
+    }
+
+    #[test]
+    fn notes_rebuild_from_their_records() {
+        let wallet = wallet(3);
+        let fvk = wallet.fvk().unwrap();
+        let record = received_note(&fvk, wallet.z_address().unwrap(), 5, &[1; 32]);
+        let note = record.try_to_note().unwrap();
+        assert_eq!(note.value().inner(), 5);
+        assert_eq!(
+            ExtractedNoteCommitment::from(note.commitment()).to_bytes(),
+            record.cmx
+        );
+        assert_eq!(note.nullifier(&fvk).to_bytes(), record.nullifier);
+
+        let mut malformed = record;
+        malformed.recipient = "00".to_string();
+        assert!(matches!(
+            malformed.try_to_note(),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn version_0_notes_are_migrated() {
+        let mut wallet = wallet(4);
+        let fvk = wallet.fvk().unwrap();
+        let change = fvk.address_at(0u32, keys::Scope::Internal);
+        let current = received_note(&fvk, change, 5, &[1; 32]);
+        let v0 = serde_json::json!({
+            "value": 5,
+            "nf": current.rho,
+            "rseed": current.rseed,
+            "internal": true,
+        });
+        wallet.notes = vec![serde_json::from_value(v0).unwrap()];
+        wallet.migrate_notes();
+
+        let note = &wallet.notes[0];
+        assert_eq!(note.version, NOTE_VERSION);
+        assert_eq!(note.recipient, current.recipient);
+        assert_eq!(note.cmx, current.cmx);
+        assert_eq!(note.nullifier, current.nullifier);
+    }
+
+    #[test]
+    fn watch_only_wallets_see_spends_only_with_a_full_viewing_key() {
+        let fvk = wallet(6).fvk().unwrap();
+        let full = Wallet::watch_only(&encoding::encode_fvk(&fvk)).unwrap();
+        let incoming =
+            Wallet::watch_only(&encoding::encode_ivk(&fvk.to_ivk(keys::Scope::External))).unwrap();
+
+        assert!(full.is_watch_only());
+        assert!(matches!(full.sk(), Err(Error::MissingKey(_))));
+        assert_eq!(full.get_z_address(), wallet(6).get_z_address());
+        assert_eq!(incoming.get_z_address(), wallet(6).get_z_address());
+        assert!(full.detects_spends());
+        assert!(!incoming.detects_spends());
+        assert_eq!(incoming.ivks().len(), 1);
+    }
+
+    #[test]
+    fn diversified_addresses_share_the_viewing_key() {
+        let mut wallet = wallet(5);
+        let default = wallet.z_address().unwrap();
+        let first = wallet.new_z_address().unwrap();
+        let second = wallet.new_z_address().unwrap();
+        assert_eq!(wallet.diversifiers, vec![1, 2]);
+        assert!(first != default && second != default && first != second);
+
+        let fvk = wallet.fvk().unwrap();
+        for address in [default, first, second] {
+            assert_eq!(fvk.scope_for_address(&address), Some(keys::Scope::External));
+        }
+    }
+
+    #[test]
+    fn accounts_derive_deterministically_from_the_seed() {
+        let seed = [7; 64];
+        let first = Wallet::from_seed(&seed, 0).unwrap();
+        let again = Wallet::from_seed(&seed, 0).unwrap();
+        let second = Wallet::from_seed(&seed, 1).unwrap();
+        assert_eq!(first.get_address(), again.get_address());
+        assert_eq!(first.get_z_address(), again.get_z_address());
+        assert_ne!(first.get_address(), second.get_address());
+        assert_ne!(first.get_z_address(), second.get_z_address());
+        assert_eq!((first.account, second.account), (Some(0), Some(1)));
+
+        // Hardened derivation only has room for indices below 2^31.
+        assert!(matches!(
+            Wallet::from_seed(&seed, 1 << 31),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+}
@@ -1,82 +1,953 @@
-use crate::wallet::Wallet;
+use crate::coinselect::Outpoint;
+use crate::crypto;
+use crate::encoding;
+use crate::error::{Error, Result};
+use crate::merkle::NoteTree;
+use crate::wallet::{self, Wallet};
+use bip39::Mnemonic;
+use fs2::FileExt;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use secp256k1::SecretKey;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
-use std::fs::File;
-use std::io;
-use std::io::Read;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
 use std::path::Path;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const WALLET_FILE: &str = "wallets.dat";
+/// Directory holding the named wallet files chosen with `--wallet`.
+const WALLET_DIR: &str = "wallets";
 
-#[derive(Serialize, Deserialize)]
+/// The wallet file this run works on, chosen once at startup by [`select_wallet`].
+static SELECTED_FILE: OnceLock<String> = OnceLock::new();
+
+/// How the secrets of an encrypted wallet file are protected.
+#[derive(Serialize, Deserialize, Clone)]
+struct Encryption {
+    /// Hex Argon2 salt the key is derived with.
+    salt: String,
+    /// The mnemonic encrypted under the key; decrypting it checks the passphrase.
+    mnemonic: String,
+    /// The unlocked session, if any.
+    #[serde(default)]
+    session: Option<Session>,
+}
+
+/// An unlocked wallet file. The derived key is sealed under a random session key that only
+/// the session file holds, so the key itself never reaches the disk, and deleting the
+/// session file on lock or expiry leaves the seal useless.
+#[derive(Serialize, Deserialize, Clone)]
+struct Session {
+    /// The derived key, encrypted under the session key.
+    key: String,
+    /// Unix time after which the session is no longer valid.
+    expires: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct Wallets {
-    wallets: HashMap<String, Wallet>,
-    zwallets: HashMap<String, String>,
+    /// The accounts of this wallet, each with its own keys, notes and sync point. An
+    /// account's number is its position: accounts are numbered in the order they were
+    /// derived from the mnemonic or imported.
+    #[serde(default)]
+    accounts: Vec<Wallet>,
+    /// The account owning each shielded address.
+    #[serde(default)]
+    z_accounts: HashMap<String, usize>,
+    /// Older files kept every account at the top level, keyed by its transparent address
+    /// or z-address, with shielded addresses mapped to those keys. Both are moved into
+    /// `accounts` on load.
+    #[serde(default, rename = "wallets", skip_serializing)]
+    legacy_wallets: HashMap<String, Wallet>,
+    #[serde(default, rename = "zwallets", skip_serializing)]
+    legacy_zwallets: HashMap<String, String>,
+    /// Stored under a new name since the tree became incremental; the leaf list older
+    /// files keep under `tree` is ignored and the tree rebuilt on the next scan.
+    #[serde(default, rename = "note_tree")]
+    tree: NoteTree,
+    /// BIP-39 mnemonic every derived wallet in the file comes from, generated with the
+    /// first wallet.
+    #[serde(default)]
+    mnemonic: String,
+    /// Next account index to derive from the mnemonic.
+    #[serde(default)]
+    next_account: u32,
+    #[serde(default)]
+    encryption: Option<Encryption>,
+    /// Transparent outputs, as `txid:vout`, that coin selection must not spend.
+    #[serde(default)]
+    locked_outputs: BTreeSet<String>,
+    /// Address book: labels of owned and external addresses, by address. Shielded
+    /// addresses are stored in their Bech32m encoding.
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    /// Encryption key of an unlocked file, used to encrypt wallets created meanwhile.
+    #[serde(skip)]
+    key: Option<[u8; 32]>,
 }
 
 impl Wallets {
-    pub fn new() -> Self {
-        Self::load_from_file().unwrap()
+    /// Loads the selected wallet file, or an empty set of wallets if there is none yet.
+    pub fn new() -> Result<Self> {
+        Self::load_from_file()
+    }
+
+    /// Adds an account derived at the next index, generating the file's mnemonic first if
+    /// it has none yet. `birthday` is the current chain height.
+    pub fn create_wallet(&mut self, birthday: u64) -> Result<String> {
+        if self.is_locked() {
+            return Err(Error::WalletLocked);
+        }
+        if self.mnemonic.is_empty() {
+            let mut entropy = [0u8; 32];
+            OsRng.fill_bytes(&mut entropy);
+            self.mnemonic = Mnemonic::from_entropy(&entropy)
+                .map_err(|e| Error::InvalidInput(format!("cannot generate a mnemonic: {}", e)))?
+                .to_string();
+        }
+        let seed = Mnemonic::parse(&self.mnemonic)
+            .map_err(|e| Error::CorruptDatabase(format!("invalid mnemonic: {}", e)))?
+            .to_seed("");
+        let mut wallet = Wallet::from_seed(&seed, self.next_account)?;
+        wallet.set_birthday(birthday);
+        self.next_account += 1;
+
+        Ok(self.insert(wallet))
+    }
+
+    /// Re-derives the first `accounts` accounts from `mnemonic`. Scanning for them starts at
+    /// `birthday`, the height before which they received nothing.
+    pub fn restore_wallet(
+        &mut self,
+        mnemonic: &str,
+        accounts: u32,
+        birthday: u64,
+    ) -> Result<Vec<String>> {
+        if !self.mnemonic.is_empty() || self.encryption.is_some() {
+            return Err(Error::InvalidInput(
+                "wallet file already has a mnemonic".to_string(),
+            ));
+        }
+        let parsed = Mnemonic::parse(mnemonic)
+            .map_err(|e| Error::InvalidInput(format!("invalid mnemonic: {}", e)))?;
+        self.mnemonic = parsed.to_string();
+
+        let seed = parsed.to_seed("");
+        let mut addresses = vec![];
+        for account in 0..accounts {
+            let mut wallet = Wallet::from_seed(&seed, account)?;
+            wallet.set_birthday(birthday);
+            addresses.push(self.insert(wallet));
+        }
+        self.next_account = accounts;
+
+        Ok(addresses)
+    }
+
+    /// Every account, by account number.
+    pub fn all(&self) -> Vec<&Wallet> {
+        self.accounts.iter().collect()
+    }
+
+    /// The shielded addresses issued to account number `account`.
+    pub fn z_addresses_of(&self, account: usize) -> Vec<String> {
+        let mut zaddrs: Vec<String> = self
+            .z_accounts
+            .iter()
+            .filter(|(_, owner)| **owner == account)
+            .map(|(zaddr, _)| zaddr.clone())
+            .collect();
+        zaddrs.sort();
+        zaddrs
+    }
+
+    pub fn mnemonic(&self) -> &str {
+        &self.mnemonic
+    }
+
+    /// Encrypts every account's keys and the mnemonic under `passphrase`. The file is
+    /// written without plaintext secrets from then on.
+    pub fn encrypt_wallet(&mut self, passphrase: &str) -> Result<()> {
+        if self.encryption.is_some() {
+            return Err(Error::InvalidInput(
+                "wallet file is already encrypted".to_string(),
+            ));
+        }
+        let salt = crypto::random_salt();
+        let key = crypto::derive_key(passphrase, &salt);
+        for wallet in self.accounts.iter_mut() {
+            wallet.encrypt(&key);
+        }
+        self.encryption = Some(Encryption {
+            salt: hex::encode(salt),
+            mnemonic: crypto::encrypt(&key, self.mnemonic.as_bytes()),
+            session: None,
+        });
+        Ok(())
+    }
+
+    /// Checks `passphrase` and keeps the wallet file unlocked for `timeout` seconds. The
+    /// session is recorded in the file, so it has to be saved afterwards.
+    pub fn unlock(&mut self, passphrase: &str, timeout: u64) -> Result<()> {
+        let key = self.key_for(passphrase)?;
+        let encryption = self
+            .encryption
+            .as_mut()
+            .ok_or_else(|| Error::InvalidInput("wallet file is not encrypted".to_string()))?;
+
+        let mut session_key = [0u8; 32];
+        OsRng.fill_bytes(&mut session_key);
+        write_session_key(&session_key)?;
+        encryption.session = Some(Session {
+            key: crypto::encrypt(&session_key, &key),
+            expires: now() + timeout,
+        });
+        Ok(())
+    }
+
+    /// Derives the file's key from `passphrase`, checking it against the encrypted mnemonic.
+    fn key_for(&self, passphrase: &str) -> Result<[u8; 32]> {
+        let encryption = self
+            .encryption
+            .as_ref()
+            .ok_or_else(|| Error::InvalidInput("wallet file is not encrypted".to_string()))?;
+        let salt = hex::decode(&encryption.salt)
+            .map_err(|e| Error::InvalidInput(format!("invalid salt: {}", e)))?;
+        let key = crypto::derive_key(passphrase, &salt);
+        crypto::decrypt(&key, &encryption.mnemonic)?;
+        Ok(key)
+    }
+
+    /// Ends any unlocked session. The file has to be saved afterwards.
+    pub fn lock(&mut self) -> Result<()> {
+        if Path::new(&session_file()).exists() {
+            fs::remove_file(session_file())?;
+        }
+        if let Some(encryption) = self.encryption.as_mut() {
+            encryption.session = None;
+        }
+        self.key = None;
+        Ok(())
+    }
+
+    fn is_locked(&self) -> bool {
+        self.encryption.is_some() && self.key.is_none()
+    }
+
+    /// Decrypts the secrets with the key of a live session, if there is one. An expired
+    /// session, or a session file not matching the recorded session, is ended.
+    fn resume_session(&mut self) -> Result<()> {
+        let encryption = match &self.encryption {
+            Some(encryption) => encryption.clone(),
+            None => return Ok(()),
+        };
+        let (session, session_key) = match (encryption.session, read_session_key()?) {
+            (Some(session), Some(session_key)) => (session, session_key),
+            (None, None) => return Ok(()),
+            _ => return self.lock(),
+        };
+        if session.expires <= now() {
+            return self.lock();
+        }
+        let key: [u8; 32] = match crypto::decrypt(&session_key, &session.key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+        {
+            Some(key) => key,
+            None => return self.lock(),
+        };
+        self.decrypt_secrets(key)
+    }
+
+    /// Decrypts the mnemonic and every account's keys with the file's key.
+    fn decrypt_secrets(&mut self, key: [u8; 32]) -> Result<()> {
+        let encrypted = match &self.encryption {
+            Some(encryption) => &encryption.mnemonic,
+            None => return Ok(()),
+        };
+        let mnemonic = crypto::decrypt(&key, encrypted)?;
+        self.mnemonic = String::from_utf8(mnemonic)
+            .map_err(|_| Error::InvalidInput("malformed encrypted mnemonic".to_string()))?;
+        for wallet in self.accounts.iter_mut() {
+            wallet.decrypt(&key)?;
+        }
+        self.key = Some(key);
+        Ok(())
     }
 
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
-        let address = wallet.get_address();
+    /// Adds an account under the next account number and returns its transparent address
+    /// or, lacking one, its z-address.
+    fn insert(&mut self, mut wallet: Wallet) -> String {
+        if let Some(key) = &self.key {
+            wallet.encrypt(key);
+        }
         let zaddr = wallet.get_z_address();
+        let address = match &zaddr {
+            Some(zaddr) if !wallet.has_transparent() => zaddr.clone(),
+            _ => wallet.get_address(),
+        };
 
-        self.wallets.insert(address.clone(), wallet);
-        self.zwallets.insert(zaddr, address.clone());
+        if let Some(zaddr) = zaddr {
+            self.z_accounts.insert(zaddr, self.accounts.len());
+        }
+        self.accounts.push(wallet);
 
         address
     }
 
+    /// Adds an imported account that starts scanning at `birthday`, refusing keys already
+    /// in the file.
+    fn import(&mut self, mut wallet: Wallet, birthday: u64) -> Result<String> {
+        if self.is_locked() && !wallet.is_watch_only() {
+            return Err(Error::WalletLocked);
+        }
+        if self.contains(&wallet) {
+            return Err(Error::InvalidInput(
+                "key is already in the wallet".to_string(),
+            ));
+        }
+        wallet.set_birthday(birthday);
+        Ok(self.insert(wallet))
+    }
+
+    fn contains(&self, wallet: &Wallet) -> bool {
+        (wallet.has_transparent() && self.get_wallet(&wallet.get_address()).is_some())
+            || wallet
+                .get_z_address()
+                .is_some_and(|zaddr| self.z_accounts.contains_key(&zaddr))
+    }
+
+    /// Imports a Bech32m viewing key as a watch-only account, returning its z-address.
+    pub fn import_viewing_key(&mut self, viewing_key: &str, birthday: u64) -> Result<String> {
+        self.import(Wallet::watch_only(viewing_key)?, birthday)
+    }
+
+    /// Imports a hex secp256k1 private key as a transparent-only wallet.
+    pub fn import_priv_key(&mut self, private_key: &str, birthday: u64) -> Result<String> {
+        let key = parse_private_key(private_key)?;
+        self.import(Wallet::from_keys(Some(&key), None), birthday)
+    }
+
+    /// Imports a Bech32m Orchard spending key as a shielded-only wallet.
+    pub fn import_spending_key(&mut self, spend_key: &str, birthday: u64) -> Result<String> {
+        let key = encoding::decode_spending_key(spend_key)?;
+        self.import(Wallet::from_keys(None, Some(&key)), birthday)
+    }
+
+    /// Imports every key in a file written by [`Wallets::dump`], each scanning from the
+    /// birthday recorded with it. Keys already in the wallet are skipped.
+    pub fn import_dump(&mut self, dump: &str) -> Result<Vec<String>> {
+        let mut addresses = vec![];
+        for (n, line) in dump.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut private_key = None;
+            let mut spend_key = None;
+            let mut viewing_key = None;
+            let mut birthday = 0;
+            for field in line.split_whitespace() {
+                let bad =
+                    || Error::InvalidInput(format!("line {}: invalid field '{}'", n + 1, field));
+                let (name, value) = field.split_once('=').ok_or_else(bad)?;
+                match name {
+                    "privkey" => private_key = Some(parse_private_key(value)?),
+                    "spendkey" => spend_key = Some(encoding::decode_spending_key(value)?),
+                    "viewkey" => viewing_key = Some(value),
+                    "birthday" => birthday = value.parse().map_err(|_| bad())?,
+                    _ => return Err(bad()),
+                }
+            }
+            let wallet = match viewing_key {
+                Some(viewing_key) => Wallet::watch_only(viewing_key)?,
+                None => Wallet::from_keys(private_key.as_ref(), spend_key.as_ref()),
+            };
+            if !self.contains(&wallet) {
+                addresses.push(self.import(wallet, birthday)?);
+            }
+        }
+        Ok(addresses)
+    }
+
+    /// Writes every key in the file as text, one account per line with its birthday, in
+    /// account order, in the format [`Wallets::import_dump`] reads.
+    pub fn dump(&self) -> Result<String> {
+        if self.is_locked() {
+            return Err(Error::WalletLocked);
+        }
+        let mut dump = String::from("# tinyzcash wallet dump\n");
+        if !self.mnemonic.is_empty() {
+            dump += &format!("# mnemonic: {}\n", self.mnemonic);
+        }
+        for wallet in &self.accounts {
+            let mut fields = vec![];
+            let mut comment = vec![];
+            if wallet.is_watch_only() {
+                fields.push(format!("viewkey={}", wallet.viewing_key));
+            }
+            if wallet.has_transparent() {
+                fields.push(format!("privkey={}", wallet.secret_key()?));
+                comment.push(format!("addr={}", wallet.get_address()));
+            }
+            if let Ok(sk) = wallet.sk() {
+                fields.push(format!("spendkey={}", encoding::encode_spending_key(&sk)));
+            }
+            fields.push(format!("birthday={}", wallet.birthday));
+            if let Some(zaddr) = wallet.get_z_address() {
+                comment.push(format!("zaddr={}", zaddr));
+            }
+            if let Some(account) = wallet.account {
+                comment.push(format!("account={}", account));
+            }
+            dump += &format!("{} # {}\n", fields.join(" "), comment.join(" "));
+        }
+        Ok(dump)
+    }
+
+    /// Writes a copy of the wallet file to a new file at `path`, encrypted if the file is.
+    /// An existing file at `path` is left alone and reported as an error.
+    pub fn backup(&self, path: &str) -> Result<()> {
+        let mut file = create_private_file(path)?;
+        file.write_all(self.contents()?.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Issues a new diversified shielded address for the account owning `address`, which
+    /// may be its transparent or any of its shielded addresses.
+    pub fn get_new_z_address(&mut self, address: &str) -> Result<String> {
+        let account = match self.account_of(address) {
+            Some(account) => account,
+            None => {
+                let zaddr = encoding::encode_address(&wallet::parse_z_address(address)?);
+                *self
+                    .z_accounts
+                    .get(&zaddr)
+                    .ok_or_else(|| Error::UnknownAddress(address.to_string()))?
+            }
+        };
+        let zaddr = self.accounts[account]
+            .new_z_address()
+            .ok_or_else(|| Error::MissingKey(format!("'{}' has no shielded address", address)))?;
+        let zaddr = encoding::encode_address(&zaddr);
+
+        self.z_accounts.insert(zaddr.clone(), account);
+
+        Ok(zaddr)
+    }
+
     pub fn get_addresses(&self) -> Vec<String> {
-        self.wallets.keys().cloned().collect()
+        self.accounts
+            .iter()
+            .filter(|w| w.has_transparent())
+            .map(Wallet::get_address)
+            .collect()
     }
 
     pub fn get_z_addresses(&self) -> Vec<String> {
-        self.zwallets.keys().cloned().collect()
+        let mut zaddrs: Vec<String> = self.z_accounts.keys().cloned().collect();
+        zaddrs.sort();
+        zaddrs
     }
 
+    /// Looks up the account owning a transparent address.
     pub fn get_wallet(&self, address: &str) -> Option<&Wallet> {
-        self.wallets.get(address)
+        self.accounts.get(self.account_of(address)?)
     }
 
-    pub fn get_mut_wallet(&mut self, address: &str) -> &mut Wallet {
-        self.wallets.get_mut(address).unwrap()
+    /// Looks up the account owning a shielded address, given in either accepted encoding.
+    pub fn get_z_wallet(&self, address: &str) -> Option<&Wallet> {
+        let zaddr = encoding::encode_address(&wallet::parse_z_address(address).ok()?);
+        self.accounts.get(*self.z_accounts.get(&zaddr)?)
     }
 
-    pub fn get_mut_z_wallet(&mut self, address: &str) -> &mut Wallet {
-        let addr = self.zwallets.get(address).unwrap();
-        self.wallets.get_mut(addr).unwrap()
+    /// The number of the account owning a transparent address.
+    fn account_of(&self, address: &str) -> Option<usize> {
+        self.accounts
+            .iter()
+            .position(|w| w.has_transparent() && w.get_address() == address)
     }
 
-    pub fn get_z_wallet(&self, address: &str) -> Option<&Wallet> {
-        let addr = self.zwallets.get(address).unwrap();
-        self.wallets.get(addr)
+    /// Excludes an output from coin selection until it is unlocked again.
+    pub fn lock_output(&mut self, outpoint: &Outpoint) {
+        self.locked_outputs.insert(outpoint.to_string());
+    }
+
+    /// Returns whether the output was locked.
+    pub fn unlock_output(&mut self, outpoint: &Outpoint) -> bool {
+        self.locked_outputs.remove(&outpoint.to_string())
     }
 
-    fn load_from_file() -> io::Result<Self> {
-        if Path::new(WALLET_FILE).exists() {
-            let mut file = File::open(WALLET_FILE)?;
+    pub fn is_locked_output(&self, outpoint: &Outpoint) -> bool {
+        self.locked_outputs.contains(&outpoint.to_string())
+    }
+
+    /// Labels an owned or external address, replacing its previous label; an empty label
+    /// removes it. Labels must be unique, as they stand in for the address on the command
+    /// line. Returns the address in its canonical encoding.
+    pub fn set_label(&mut self, address: &str, label: &str) -> Result<String> {
+        let address = canonical_address(address)?;
+        if label.is_empty() {
+            self.labels.remove(&address);
+            return Ok(address);
+        }
+        if canonical_address(label).is_ok() {
+            return Err(Error::InvalidInput(format!(
+                "label '{}' is itself an address",
+                label
+            )));
+        }
+        if let Some(other) = self.address_of(label).filter(|a| **a != address) {
+            return Err(Error::InvalidInput(format!(
+                "label '{}' is already used for '{}'",
+                label, other
+            )));
+        }
+        self.labels.insert(address.clone(), label.to_string());
+        Ok(address)
+    }
+
+    pub fn label(&self, address: &str) -> Option<&str> {
+        let address = canonical_address(address).ok()?;
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    /// The labelled addresses that do not belong to the wallet, sorted by address.
+    pub fn contacts(&self) -> Vec<(&String, &String)> {
+        self.labels
+            .iter()
+            .filter(|(a, _)| self.get_wallet(a).is_none() && self.get_z_wallet(a).is_none())
+            .collect()
+    }
+
+    /// Resolves a label to its address; anything that is not a label is returned as is.
+    pub fn resolve(&self, address_or_label: &str) -> String {
+        self.address_of(address_or_label)
+            .cloned()
+            .unwrap_or_else(|| address_or_label.to_string())
+    }
+
+    fn address_of(&self, label: &str) -> Option<&String> {
+        self.labels
+            .iter()
+            .find(|(_, l)| l.as_str() == label)
+            .map(|(a, _)| a)
+    }
+
+    pub fn tree(&self) -> &NoteTree {
+        &self.tree
+    }
+
+    /// Borrows the accounts together with the note commitment tree, for the scanner.
+    pub fn wallets_and_tree_mut(&mut self) -> (impl Iterator<Item = &mut Wallet>, &mut NoteTree) {
+        (self.accounts.iter_mut(), &mut self.tree)
+    }
+
+    fn load_from_file() -> Result<Self> {
+        if Path::new(wallet_file()).exists() {
+            let mut file = File::open(wallet_file())?;
             let mut content = String::new();
             file.read_to_string(&mut content)?;
 
-            let wallets: Wallets = serde_json::from_str(&content)?;
+            let mut wallets: Wallets = serde_json::from_str(&content)?;
+            wallets.migrate_accounts();
+            for wallet in wallets.accounts.iter_mut() {
+                wallet.migrate_notes();
+            }
+            wallets.resume_session()?;
 
             Ok(wallets)
         } else {
-            Ok(Wallets {
-                wallets: HashMap::new(),
-                zwallets: HashMap::new(),
-            })
+            Ok(Wallets::default())
+        }
+    }
+
+    /// Numbers the accounts of a file that kept them by address: those derived from the
+    /// mnemonic first, by derivation index, then imported ones by birthday.
+    fn migrate_accounts(&mut self) {
+        let mut legacy: Vec<(String, Wallet)> = self.legacy_wallets.drain().collect();
+        legacy.sort_by(|(a, x), (b, y)| {
+            let key = |w: &Wallet| (w.account.is_none(), w.account, w.birthday);
+            key(x).cmp(&key(y)).then_with(|| a.cmp(b))
+        });
+        let mut numbers = HashMap::new();
+        for (address, wallet) in legacy {
+            numbers.insert(address, self.accounts.len());
+            self.accounts.push(wallet);
+        }
+        for (zaddr, owner) in self.legacy_zwallets.drain() {
+            // Older files also keyed shielded addresses by the hex of their raw bytes.
+            let zaddr = match wallet::parse_z_address(&zaddr) {
+                Ok(parsed) => encoding::encode_address(&parsed),
+                Err(_) => zaddr,
+            };
+            if let Some(&account) = numbers.get(&owner) {
+                self.z_accounts.insert(zaddr, account);
+            }
+        }
+    }
+
+    pub fn save_to_file(&self) -> Result<()> {
+        replace_file(wallet_file(), &self.contents()?)
+    }
+
+    /// The file contents, without the secrets of an encrypted file.
+    fn contents(&self) -> Result<String> {
+        if self.encryption.is_some() {
+            let mut locked = self.clone();
+            locked.mnemonic.clear();
+            for wallet in locked.accounts.iter_mut() {
+                wallet.strip_secrets();
+            }
+            Ok(serde_json::to_string(&locked)?)
+        } else {
+            Ok(serde_json::to_string(self)?)
+        }
+    }
+}
+
+/// Replaces the file at `path` by renaming a fully written copy over it, so a crash leaves
+/// either the old or the new contents, never a mix.
+fn replace_file(path: &str, content: &str) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = File::create(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    #[cfg(unix)]
+    {
+        let dir = Path::new(path)
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty());
+        File::open(dir.unwrap_or(Path::new(".")))?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Validates a transparent or shielded address, encoding shielded ones as Bech32m.
+fn canonical_address(address: &str) -> Result<String> {
+    if wallet::validate_address(address) {
+        return Ok(address.to_string());
+    }
+    wallet::parse_z_address(address)
+        .map(|zaddr| encoding::encode_address(&zaddr))
+        .map_err(|_| Error::InvalidAddress(address.to_string()))
+}
+
+/// Parses a hex secp256k1 private key.
+pub fn parse_private_key(private_key: &str) -> Result<SecretKey> {
+    hex::decode(private_key)
+        .ok()
+        .and_then(|bytes| SecretKey::from_slice(&bytes).ok())
+        .ok_or_else(|| Error::InvalidInput("invalid private key".to_string()))
+}
+
+/// Chooses the named wallet file `wallets/<name>.dat` for this run instead of the default
+/// `wallets.dat`. Must be called before any wallets are loaded.
+pub fn select_wallet(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(Error::InvalidInput(format!(
+            "invalid wallet name '{}': use letters, digits, '-' and '_'",
+            name
+        )));
+    }
+    fs::create_dir_all(WALLET_DIR)?;
+    SELECTED_FILE
+        .set(format!("{}/{}.dat", WALLET_DIR, name))
+        .map_err(|_| Error::InvalidInput("a wallet file was already selected".to_string()))
+}
+
+fn wallet_file() -> &'static str {
+    SELECTED_FILE.get().map_or(WALLET_FILE, |f| f.as_str())
+}
+
+/// Holds the session key of an unlocked wallet file until it is locked or expires. The CLI
+/// exits after every command, so the unlocked state has to outlive the process.
+fn session_file() -> String {
+    format!("{}.session", wallet_file().trim_end_matches(".dat"))
+}
+
+fn lock_file() -> String {
+    format!("{}.lock", wallet_file().trim_end_matches(".dat"))
+}
+
+/// Present while the wallet file may be behind a block this run mined: it is created
+/// before mining and removed by the next successful [`scanner::sync`], so a run that dies
+/// in between leaves it for the next run to find.
+///
+/// [`scanner::sync`]: crate::scanner::sync
+fn pending_sync_file() -> String {
+    format!("{}.pending", wallet_file().trim_end_matches(".dat"))
+}
+
+/// Records, before a block is mined, that the wallets must be synced with it.
+pub fn begin_chain_update() -> Result<()> {
+    File::create(pending_sync_file())?.sync_all()?;
+    Ok(())
+}
+
+/// Whether a block was mined without the wallets being synced with it afterwards.
+pub fn chain_update_pending() -> bool {
+    Path::new(&pending_sync_file()).exists()
+}
+
+/// Clears the mark left by [`begin_chain_update`] once the wallets are synced.
+pub(crate) fn end_chain_update() -> Result<()> {
+    match fs::remove_file(pending_sync_file()) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn read_session_key() -> Result<Option<[u8; 32]>> {
+    let content = match fs::read_to_string(session_file()) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(hex::decode(content.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok()))
+}
+
+fn write_session_key(key: &[u8; 32]) -> Result<()> {
+    // Replace any old file, so the new one gets owner-only permissions.
+    if Path::new(&session_file()).exists() {
+        fs::remove_file(session_file())?;
+    }
+    create_private_file(&session_file())?.write_all(hex::encode(key).as_bytes())?;
+    Ok(())
+}
+
+/// Creates `path` readable and writable by its owner only, failing if it already exists.
+pub fn create_private_file(path: &str) -> Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    Ok(options.open(path)?)
+}
+
+/// Takes an exclusive lock on the selected wallet file, held until the returned file is
+/// dropped, so concurrent runs cannot interleave reading and saving it.
+pub fn acquire_lock() -> Result<File> {
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_file())?;
+    file.lock_exclusive()?;
+    Ok(file)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::tests::wallet;
+
+    /// A path under the system's temporary directory with no file at it yet.
+    fn temp_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("tinyzcash-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn restoring_the_mnemonic_derives_the_same_accounts() {
+        let mut created = Wallets::default();
+        let first = created.create_wallet(0).unwrap();
+        let second = created.create_wallet(0).unwrap();
+
+        let mut restored = Wallets::default();
+        let addresses = restored.restore_wallet(created.mnemonic(), 2, 0).unwrap();
+        assert_eq!(addresses, vec![first, second]);
+        // Accounts created afterwards continue the sequence.
+        assert_eq!(
+            restored.create_wallet(0).unwrap(),
+            created.create_wallet(0).unwrap()
+        );
+    }
+
+    #[test]
+    fn bad_mnemonics_are_errors() {
+        let mut corrupt = Wallets {
+            mnemonic: "abandon abandon abandon abandon".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            corrupt.create_wallet(0),
+            Err(Error::CorruptDatabase(_))
+        ));
+        assert!(matches!(
+            Wallets::default().restore_wallet("not a mnemonic", 1, 0),
+            Err(Error::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn encrypted_secrets_decrypt_with_the_passphrase() {
+        let mut wallets = Wallets::default();
+        let address = wallets.create_wallet(0).unwrap();
+        let mnemonic = wallets.mnemonic().to_string();
+        let secret = wallets.get_wallet(&address).unwrap().secret_key().unwrap();
+        wallets.encrypt_wallet("hunter2").unwrap();
+
+        // The file holds no plaintext secrets, and nothing can be derived from it.
+        let content = wallets.contents().unwrap();
+        assert!(!content.contains(&mnemonic) && !content.contains(&secret));
+        let mut saved: Wallets = serde_json::from_str(&content).unwrap();
+        assert!(matches!(saved.create_wallet(0), Err(Error::WalletLocked)));
+        assert!(saved.key_for("hunter3").is_err());
+
+        let key = saved.key_for("hunter2").unwrap();
+        saved.decrypt_secrets(key).unwrap();
+        assert_eq!(saved.mnemonic(), mnemonic);
+        let wallet = saved.get_wallet(&address).unwrap();
+        assert_eq!(wallet.secret_key().unwrap(), secret);
+    }
+
+    #[test]
+    fn replacing_a_file_leaves_no_partial_copy() {
+        let path = temp_path("replace");
+        // A copy left by a crash while writing is discarded.
+        fs::write(format!("{}.tmp", path), "partial").unwrap();
+        replace_file(&path, "old").unwrap();
+        replace_file(&path, "new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn dumped_keys_import_into_another_file() {
+        let mut wallets = Wallets::default();
+        let derived = wallets.create_wallet(2).unwrap();
+        let transparent = wallets.import_priv_key(&hex::encode([3; 32]), 4).unwrap();
+        let fvk = wallet(5).fvk().unwrap();
+        let watched = wallets
+            .import_viewing_key(&encoding::encode_fvk(&fvk), 6)
+            .unwrap();
+        let dump = wallets.dump().unwrap();
+
+        let mut imported = Wallets::default();
+        let addresses = imported.import_dump(&dump).unwrap();
+        assert_eq!(addresses, vec![derived, transparent, watched]);
+        let birthdays: Vec<u64> = imported.all().iter().map(|w| w.birthday).collect();
+        assert_eq!(birthdays, vec![2, 4, 6]);
+        assert!(imported.all()[2].is_watch_only());
+        // Keys already in the file are skipped.
+        assert!(imported.import_dump(&dump).unwrap().is_empty());
+    }
+
+    #[test]
+    fn backups_are_private_and_never_overwrite() {
+        let path = temp_path("backup");
+        let wallets = Wallets::default();
+        wallets.backup(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            wallets.contents().unwrap()
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::write(&path, "kept").unwrap();
+        assert!(wallets.backup(&path).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "kept");
+    }
+
+    #[test]
+    fn legacy_files_migrate_to_numbered_accounts() {
+        let seed = [1; 64];
+        let first = Wallet::from_seed(&seed, 0).unwrap();
+        let second = Wallet::from_seed(&seed, 1).unwrap();
+        let mut imported = wallet(3);
+        imported.birthday = 5;
+        let owner = |w: &Wallet| w.get_address();
+        let legacy = serde_json::json!({
+            "wallets": {
+                owner(&imported): imported,
+                owner(&second): second,
+                owner(&first): first,
+            },
+            "zwallets": {
+                // Older files also keyed shielded addresses by their raw bytes.
+                hex::encode(first.z_address().unwrap().to_raw_address_bytes()): owner(&first),
+                second.get_z_address().unwrap(): owner(&second),
+                imported.get_z_address().unwrap(): owner(&imported),
+            },
+            "next_account": 2,
+        });
+        let mut wallets: Wallets = serde_json::from_value(legacy).unwrap();
+        wallets.migrate_accounts();
+
+        let addresses: Vec<String> = wallets.all().iter().map(|w| owner(w)).collect();
+        assert_eq!(
+            addresses,
+            vec![owner(&first), owner(&second), owner(&imported)]
+        );
+        for wallet in [&first, &second, &imported] {
+            let zaddr = wallet.get_z_address().unwrap();
+            assert_eq!(owner(wallets.get_z_wallet(&zaddr).unwrap()), owner(wallet));
         }
+        let zaddrs = wallets.get_z_addresses();
+        let mut sorted = zaddrs.clone();
+        sorted.sort();
+        assert_eq!((zaddrs.len(), zaddrs), (3, sorted));
+        // Saving drops the old layout.
+        let saved = wallets.contents().unwrap();
+        assert!(!saved.contains("\"wallets\"") && !saved.contains("\"zwallets\""));
     }
 
-    pub fn save_to_file(&self) -> io::Result<()> {
-        let content = serde_json::to_string(self)?;
+    #[test]
+    fn labels_stand_in_for_addresses() {
+        let mut wallets = Wallets::default();
+        let mine = wallets.create_wallet(0).unwrap();
+        let friend = wallet(2);
+        let zaddr = friend.get_z_address().unwrap();
+        let raw = hex::encode(friend.z_address().unwrap().to_raw_address_bytes());
+
+        assert_eq!(wallets.set_label(&raw, "friend").unwrap(), zaddr);
+        wallets.set_label(&mine, "savings").unwrap();
+        assert_eq!(wallets.resolve("friend"), zaddr);
+        assert_eq!(wallets.resolve("savings"), mine);
+        assert_eq!(wallets.resolve(&mine), mine);
+        assert_eq!(wallets.label(&raw), Some("friend"));
+        assert_eq!(wallets.contacts(), vec![(&zaddr, &"friend".to_string())]);
 
-        fs::write(WALLET_FILE, content)
+        assert!(matches!(
+            wallets.set_label(&mine, "friend"),
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(matches!(
+            wallets.set_label(&mine, &zaddr),
+            Err(Error::InvalidInput(_))
+        ));
+        wallets.set_label(&raw, "").unwrap();
+        assert_eq!(wallets.resolve("friend"), "friend");
     }
 }
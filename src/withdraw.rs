@@ -1,58 +1,129 @@
-use crate::wallets::Wallets;
-use bridgetree::BridgeTree;
-use orchard::builder::Builder;
-use orchard::bundle::{Authorized, Flags};
-use orchard::circuit::ProvingKey;
-use orchard::keys::{FullViewingKey, SpendAuthorizingKey};
-use orchard::note::ExtractedNoteCommitment;
-use orchard::tree::{MerkleHashOrchard, MerklePath};
-use orchard::Bundle;
+use crate::{
+    error::{Error, Result},
+    merkle::NoteTree,
+    transaction::Transaction,
+    transaction_output::TXOutput,
+    wallet::{self, Wallet},
+    wallets::Wallets,
+    zsend,
+};
+use orchard::{
+    circuit::ProvingKey,
+    keys::{FullViewingKey, Scope, SpendAuthorizingKey},
+    value::NoteValue,
+};
 use rand::rngs::OsRng;
 
-pub fn withdraw(address: &str) -> Bundle<Authorized, i64> {
-    let wallets = Wallets::new();
-    let wallet = wallets.get_z_wallet(address).unwrap();
+/// Unshields `amount` from a shielded address to the transparent address `to`. Enough
+/// notes are spent to cover it and the rest returns to the sender's change address; the
+/// bundle's value balance releases `amount` from the pool to the transparent output.
+pub fn withdraw(from: &str, to: &str, amount: u64) -> Result<Transaction> {
+    if !wallet::validate_address(to) {
+        return Err(Error::InvalidAddress(to.to_string()));
+    }
+    let wallets = Wallets::new()?;
+    let wallet = wallets
+        .get_z_wallet(from)
+        .ok_or_else(|| Error::UnknownAddress(from.to_string()))?;
+    unshield(wallet, wallets.tree(), to, amount)
+}
 
+/// Builds the transaction [`withdraw`] mines, spending notes of `wallet` witnessed in
+/// `tree`.
+fn unshield(wallet: &Wallet, tree: &NoteTree, to: &str, amount: u64) -> Result<Transaction> {
     let mut rng = OsRng;
     let pk = ProvingKey::build();
 
-    let sk = wallet.sk();
+    let sk = wallet.sk()?;
     let fvk = FullViewingKey::from(&sk);
+    let ovk = Some(fvk.to_ovk(Scope::External));
 
-    // Create a shielding bundle.
-    let shielding_bundle: Bundle<_, i64> = {
-        let old_note = wallet.notes.get(0).unwrap();
-        let note = old_note.to_note(wallet.z_address());
-        let cmx: ExtractedNoteCommitment = note.commitment().into();
-
-        let leaf = MerkleHashOrchard::from_cmx(&cmx);
-        let mut tree = BridgeTree::<MerkleHashOrchard, u32, 32>::new(100);
-        tree.append(leaf);
-        let position = tree.mark().unwrap();
-        let root = tree.root(0).unwrap();
-        let auth_path = tree.witness(position, 0).unwrap();
-        let merkle_path = MerklePath::from_parts(
-            u64::from(position).try_into().unwrap(),
-            auth_path[..].try_into().unwrap(),
-        );
-        let anchor = root.into();
-        assert_eq!(anchor, merkle_path.root(cmx));
-
-        let mut builder = Builder::new(Flags::from_parts(true, false), anchor);
-        assert_eq!(builder.add_spend(fvk, note, merkle_path), Ok(()));
-        let unauthorized = builder.build(&mut rng).unwrap();
-        let sighash = unauthorized.commitment().into();
-        let proven = unauthorized.create_proof(&pk, &mut rng).unwrap();
-        proven
-            .apply_signatures(rng, sighash, &[SpendAuthorizingKey::from(&sk)])
-            .unwrap()
+    let (selected, total) = zsend::select_notes(&wallet.notes, amount)?;
+    let mut builder = zsend::spending_builder(&fvk, &selected, tree)?;
+    if total > amount {
+        let change = fvk.address_at(0u32, Scope::Internal);
+        builder
+            .add_recipient(ovk, change, NoteValue::from_raw(total - amount), None)
+            .map_err(Error::bundle)?;
+    }
+
+    let value = i64::try_from(amount)
+        .map_err(|_| Error::InvalidInput(format!("amount {} is out of range", amount)))?;
+    let mut tx = Transaction {
+        vout: vec![TXOutput::new(value, to)?],
+        ..Default::default()
     };
-    shielding_bundle
+    tx.set_id();
+
+    let unauthorized = builder.build(&mut rng).map_err(Error::bundle)?;
+    let proven = unauthorized
+        .create_proof(&pk, &mut rng)
+        .map_err(Error::bundle)?;
+    let sighash = tx.shielded_sighash(&proven);
+    let bundle = proven
+        .apply_signatures(rng, sighash, &[SpendAuthorizingKey::from(&sk)])
+        .map_err(Error::bundle)?;
+
+    tx.bundle = (&bundle).into();
+    tx.set_id();
+    Ok(tx)
 }
 
-pub fn save_note(address: &str) {
-    let mut wallets = Wallets::new();
-    let wallet = wallets.get_mut_z_wallet(address);
-    wallet.notes.remove(0);
-    _ = wallets.save_to_file();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::verify;
+    use crate::wallet::tests::{received_note, wallet};
+    use orchard::{
+        keys::PreparedIncomingViewingKey, note::ExtractedNoteCommitment,
+        note_encryption::OrchardDomain,
+    };
+    use zcash_note_encryption::try_note_decryption;
+
+    #[test]
+    fn withdrawals_release_the_amount_and_keep_the_change() {
+        let mut me = wallet(1);
+        let fvk = me.fvk().unwrap();
+        let mut note = received_note(&fvk, me.z_address().unwrap(), 5, &[1; 32]);
+        let mut tree = NoteTree::default();
+        let cmx = ExtractedNoteCommitment::from_bytes(&note.cmx).unwrap();
+        note.position = tree.append(&cmx);
+        tree.mark();
+        me.notes = vec![note];
+
+        let to = wallet(2).get_address();
+        let tx = unshield(&me, &tree, &to, 3).unwrap();
+        assert_eq!(tx.vout.len(), 1);
+        assert_eq!(tx.vout[0].value, 3);
+        assert_eq!(
+            tx.vout[0].pub_key_hash,
+            wallet::decode_address(&to).unwrap()
+        );
+        assert_eq!(tx.bundle.value_balance(), 3);
+        let bundle = tx.bundle.to_orchard().unwrap();
+        assert!(verify::verify_bundle(&bundle, &tx.sighash()));
+
+        // The rest of the note returns to the sender's change address.
+        let ivk = PreparedIncomingViewingKey::new(&fvk.to_ivk(Scope::Internal));
+        let change: Vec<u64> = bundle
+            .actions()
+            .iter()
+            .filter_map(|a| try_note_decryption(&OrchardDomain::for_action(a), &ivk, a))
+            .map(|(note, _, _)| note.value().inner())
+            .collect();
+        assert_eq!(change, vec![2]);
+
+        assert!(matches!(
+            unshield(&me, &tree, &to, 6),
+            Err(Error::InsufficientFunds {
+                available: 5,
+                required: 6
+            })
+        ));
+        let zaddr = me.get_z_address().unwrap();
+        assert!(matches!(
+            withdraw(&zaddr, &zaddr, 1),
+            Err(Error::InvalidAddress(_))
+        ));
+    }
 }
@@ -1,92 +1,146 @@
-use crate::{wallet, wallets::Wallets};
-use bridgetree::BridgeTree;
+use crate::{
+    error::{Error, Result},
+    memo::MEMO_SIZE,
+    merkle::NoteTree,
+    transaction::Transaction,
+    wallet,
+    wallets::Wallets,
+};
 use orchard::{
     builder::Builder,
     bundle::{Authorized, Flags},
     circuit::ProvingKey,
-    keys::{FullViewingKey, PreparedIncomingViewingKey, Scope, SpendAuthorizingKey},
-    note::ExtractedNoteCommitment,
-    note_encryption::OrchardDomain,
-    tree::{MerkleHashOrchard, MerklePath},
+    keys::{FullViewingKey, Scope, SpendAuthorizingKey},
     value::NoteValue,
     Bundle,
 };
 use rand::rngs::OsRng;
-use zcash_note_encryption::try_note_decryption;
+use std::cmp::Reverse;
 
-pub fn zsend(from: &str, to: &str) -> Bundle<Authorized, i64> {
-    let wallets = Wallets::new();
+pub fn zsend(
+    from: &str,
+    to: &str,
+    amount: u64,
+    memo: Option<[u8; MEMO_SIZE]>,
+) -> Result<Bundle<Authorized, i64>> {
+    let wallets = Wallets::new()?;
 
     let mut rng = OsRng;
-    let pk = ProvingKey::build();
 
-    let from = wallets.get_z_wallet(from).unwrap();
-    let from_sk = from.sk();
+    let from = wallets
+        .get_z_wallet(from)
+        .ok_or_else(|| Error::UnknownAddress(from.to_string()))?;
+    let from_sk = from.sk()?;
     let from_fvk = FullViewingKey::from(&from_sk);
-    let from_addr = from.z_address();
+    let change_addr = from_fvk.address_at(0u32, Scope::Internal);
+    // Outputs are also encrypted to the sender, so the scanner can recover what was sent.
+    let ovk = Some(from_fvk.to_ovk(Scope::External));
 
-    let to = wallets.get_z_wallet(to).unwrap();
-    let recipient = to.z_address();
+    let recipient = wallet::parse_z_address(to)?;
 
+    let (selected, total) = select_notes(&from.notes, amount)?;
+
+    let pk = ProvingKey::build();
     let shielded_bundle: Bundle<_, i64> = {
-        let old_note = from.notes.get(0).unwrap();
-        let note = old_note.to_note(from_addr);
-        let cmx: ExtractedNoteCommitment = note.commitment().into();
-
-        let leaf = MerkleHashOrchard::from_cmx(&cmx);
-        let mut tree = BridgeTree::<MerkleHashOrchard, u32, 32>::new(100);
-        tree.append(leaf);
-        let position = tree.mark().unwrap();
-        let root = tree.root(0).unwrap();
-        let auth_path = tree.witness(position, 0).unwrap();
-        let merkle_path = MerklePath::from_parts(
-            u64::from(position).try_into().unwrap(),
-            auth_path[..].try_into().unwrap(),
-        );
-        let anchor = root.into();
-        assert_eq!(anchor, merkle_path.root(cmx));
-
-        let mut builder = Builder::new(Flags::from_parts(true, true), anchor);
-        assert_eq!(builder.add_spend(from_fvk, note, merkle_path), Ok(()));
-        assert_eq!(
-            builder.add_recipient(None, recipient, NoteValue::from_raw(old_note.value), None),
-            Ok(())
-        );
-        let unauthorized = builder.build(&mut rng).unwrap();
-        let sighash = unauthorized.commitment().into();
-        let proven = unauthorized.create_proof(&pk, &mut rng).unwrap();
+        let mut builder = spending_builder(&from_fvk, &selected, wallets.tree())?;
+        builder
+            .add_recipient(ovk.clone(), recipient, NoteValue::from_raw(amount), memo)
+            .map_err(Error::bundle)?;
+        if total > amount {
+            builder
+                .add_recipient(ovk, change_addr, NoteValue::from_raw(total - amount), None)
+                .map_err(Error::bundle)?;
+        }
+        let unauthorized = builder.build(&mut rng).map_err(Error::bundle)?;
+        let proven = unauthorized
+            .create_proof(&pk, &mut rng)
+            .map_err(Error::bundle)?;
+        // Fully shielded transactions carry no transparent parts.
+        let sighash = Transaction::default().shielded_sighash(&proven);
         proven
             .apply_signatures(rng, sighash, &[SpendAuthorizingKey::from(&from_sk)])
-            .unwrap()
+            .map_err(Error::bundle)?
     };
-    shielded_bundle
+    Ok(shielded_bundle)
 }
 
-pub fn save_note(bundle: &Bundle<Authorized, i64>, from: &str, to: &str) {
-    let mut wallets = Wallets::new();
-    let to_wallet = wallets.get_z_wallet(to).unwrap();
-    let to_sk = to_wallet.sk();
-    let to_fvk = FullViewingKey::from(&to_sk);
-    let to_ivk = PreparedIncomingViewingKey::new(&to_fvk.to_ivk(Scope::External));
-
-    let (note, _, _) = bundle
-        .actions()
-        .iter()
-        .find_map(|action| {
-            let domain = OrchardDomain::for_action(action);
-            try_note_decryption(&domain, &to_ivk, action)
-        })
-        .unwrap();
-    let n = wallet::Note {
-        value: note.value().inner(),
-        rseed: *note.rseed().as_bytes(),
-        nf: note.rho().to_bytes(),
-    };
+/// Creates a builder spending `notes`, witnessed against the current anchor of the
+/// wallet's note commitment tree.
+pub(crate) fn spending_builder(
+    fvk: &FullViewingKey,
+    notes: &[&wallet::Note],
+    tree: &NoteTree,
+) -> Result<Builder> {
+    let positions: Vec<u64> = notes.iter().map(|n| n.position).collect();
+    let (anchor, paths) = tree.witnesses(&positions).ok_or_else(|| {
+        Error::Bundle("missing witness for a note; rescan the wallet".to_string())
+    })?;
+
+    let mut builder = Builder::new(Flags::from_parts(true, true), anchor);
+    for (note, merkle_path) in notes.iter().zip(paths) {
+        builder
+            .add_spend(fvk.clone(), note.try_to_note()?, merkle_path)
+            .map_err(Error::bundle)?;
+    }
+    Ok(builder)
+}
+
+/// Picks notes largest first until they cover `amount`, returning them with their total.
+pub(crate) fn select_notes(
+    notes: &[wallet::Note],
+    amount: u64,
+) -> Result<(Vec<&wallet::Note>, u64)> {
+    let mut candidates: Vec<&wallet::Note> = notes.iter().filter(|n| !n.is_spent()).collect();
+    candidates.sort_by_key(|n| Reverse(n.value));
+
+    let mut selected = vec![];
+    let mut total = 0;
+    for note in candidates {
+        if total >= amount {
+            break;
+        }
+        total += note.value;
+        selected.push(note);
+    }
+
+    if total < amount {
+        return Err(Error::InsufficientFunds {
+            available: total,
+            required: amount,
+        });
+    }
+    Ok((selected, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wallet::tests::{received_note, wallet};
+
+    #[test]
+    fn notes_are_selected_largest_first_skipping_spent_ones() {
+        let me = wallet(1);
+        let fvk = me.fvk().unwrap();
+        let to = me.z_address().unwrap();
+        let mut notes: Vec<wallet::Note> = [2, 5, 3, 9]
+            .into_iter()
+            .map(|value| received_note(&fvk, to, value, &[value as u8; 32]))
+            .collect();
+        notes[3].spent_in = Some("00".to_string());
+
+        let (selected, total) = select_notes(&notes, 7).unwrap();
+        let values: Vec<u64> = selected.iter().map(|n| n.value).collect();
+        assert_eq!((values, total), (vec![5, 3], 8));
 
-    let wallet = wallets.get_mut_z_wallet(to);
-    wallet.notes.push(n);
+        let (selected, total) = select_notes(&notes, 10).unwrap();
+        assert_eq!((selected.len(), total), (3, 10));
 
-    let wallet = wallets.get_mut_z_wallet(from);
-    wallet.notes.remove(0);
-    _ = wallets.save_to_file();
+        assert!(matches!(
+            select_notes(&notes, 11),
+            Err(Error::InsufficientFunds {
+                available: 10,
+                required: 11
+            })
+        ));
+    }
 }